@@ -0,0 +1,503 @@
+use std::fmt;
+
+use crate::config::{Config, ConfigError};
+use crate::modules::{Color, ColorParseError, ICONS_ENV_VAR, IconSet, NAMED_COLORS, Palette};
+use crate::template::{Template, TemplateError};
+use crate::theme::Theme;
+use crate::{Commands, MODULE_NAMES};
+
+/// 設定の問題1件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 問題のある箇所 (`config.toml: modules[1].pwd.color` など)
+    pub location: String,
+    pub message: String,
+    /// 直し方の提案
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n  help: {}", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ConfigError> for Diagnostic {
+    fn from(e: ConfigError) -> Self {
+        let (path, message) = match &e {
+            ConfigError::Io(path, e) => (path, e.to_string()),
+            ConfigError::Parse(path, e) => (path, e.message().to_string()),
+            ConfigError::Entry(path, key, message) => {
+                return Diagnostic {
+                    location: format!("{}: {}", path.display(), key),
+                    message: format!("{} (ignored)", message),
+                    suggestion: None,
+                };
+            }
+        };
+        // toml のエラーは位置をバイト単位で持っているので、行と列に直して添える
+        let location = match &e {
+            ConfigError::Parse(_, parse) => {
+                match parse.span().zip(std::fs::read_to_string(path).ok()) {
+                    Some((span, text)) => {
+                        let before = &text[..span.start.min(text.len())];
+                        let line = before.matches('\n').count() + 1;
+                        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+                        format!("{}:{}:{}", path.display(), line, column)
+                    }
+                    None => path.display().to_string(),
+                }
+            }
+            ConfigError::Io(..) | ConfigError::Entry(..) => path.display().to_string(),
+        };
+        Diagnostic {
+            location,
+            message,
+            suggestion: None,
+        }
+    }
+}
+
+/// モジュールの設定の書き方。問題の箇所を設定ファイルのキーとCLIのフラグのどちらで示すか。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Config,
+    CommandLine,
+}
+
+/// 1つのモジュールの設定を検査する。`location` はモジュールの場所 (`modules[0]` など)。
+pub fn check_command(
+    command: &Commands,
+    location: &str,
+    source: Source,
+    palette: &Palette,
+) -> Vec<Diagnostic> {
+    let key = |name: &str| match source {
+        Source::Config => format!("{}.{}.{}", location, command.name(), name),
+        Source::CommandLine => format!(
+            "{} {} --{}",
+            location,
+            command.name(),
+            name.replace('_', "-")
+        ),
+    };
+    let mut problems = Vec::new();
+
+    let color = match command {
+        Commands::Os { color, .. }
+        | Commands::Pwd { color, .. }
+        | Commands::Time { color, .. }
+        | Commands::Cmd { color, .. } => color.as_deref(),
+        Commands::Git { .. } | Commands::Custom { .. } | Commands::Env { .. } => None,
+    };
+    if let Some(color) = color
+        && let Err(e) = Color::parse_with_palette(color, palette)
+    {
+        problems.push(Diagnostic {
+            location: key("color"),
+            suggestion: color_suggestion(&e, palette),
+            message: e.to_string(),
+        });
+    }
+
+    // 文字列以外の色の項目は、パレットに無い `@name` をここで見つける
+    for (name, e) in command.clone().resolve_palette(palette) {
+        let location = key(name);
+        if problems.iter().all(|problem| problem.location != location) {
+            problems.push(Diagnostic {
+                location,
+                suggestion: color_suggestion(&e, palette),
+                message: e.to_string(),
+            });
+        }
+    }
+
+    if let Commands::Git {
+        path: Some(path), ..
+    } = command
+        && !path.exists()
+    {
+        problems.push(Diagnostic {
+            location: key("path"),
+            message: format!("{} does not exist", path.display()),
+            suggestion: Some("omit `path` to use the current directory".to_string()),
+        });
+    }
+
+    if let Commands::Custom {
+        command: None,
+        file: None,
+        ..
+    } = command
+    {
+        problems.push(Diagnostic {
+            location: key("command"),
+            message: "Nothing to show".to_string(),
+            suggestion: Some("set `command` or `file`".to_string()),
+        });
+    }
+
+    if let Commands::Env { var, .. } = command
+        && var.is_empty()
+    {
+        problems.push(Diagnostic {
+            location: key("var"),
+            message: "Nothing to show".to_string(),
+            suggestion: Some("add the names of the variables to show".to_string()),
+        });
+    }
+
+    if let Some(format) = &command.segment_options().format {
+        match Template::parse(format) {
+            Err(e) => problems.push(format_error(e, key("format"))),
+            Ok(template) => {
+                let fields = command.format_fields();
+                for name in template.fields() {
+                    if fields.contains(&name) {
+                        continue;
+                    }
+                    problems.push(Diagnostic {
+                        location: key("format"),
+                        message: format!("Unknown field `{}`", name),
+                        suggestion: Some(match closest(name, fields.iter().copied()) {
+                            Some(field) => format!("did you mean `{}`?", field),
+                            None => format!("available fields: {}", fields.join(", ")),
+                        }),
+                    });
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn format_error(e: TemplateError, location: String) -> Diagnostic {
+    Diagnostic {
+        location,
+        message: e.to_string(),
+        suggestion: Some("write `{{` and `}}` for literal braces".to_string()),
+    }
+}
+
+// 設定ファイル中の全てのモジュールと、その場所 (`modules[0]` など)
+fn config_modules(config: &Config) -> Vec<(String, &Commands)> {
+    let mut modules = Vec::new();
+    for (i, command) in config.modules.iter().enumerate() {
+        modules.push((format!("modules[{}]", i), command));
+    }
+    for (i, command) in config.right_modules.iter().enumerate() {
+        modules.push((format!("right_modules[{}]", i), command));
+    }
+    for (i, line) in config.lines.iter().enumerate() {
+        for (j, command) in line.left.iter().enumerate() {
+            modules.push((format!("lines[{}].left[{}]", i, j), command));
+        }
+        for (j, command) in line.right.iter().enumerate() {
+            modules.push((format!("lines[{}].right[{}]", i, j), command));
+        }
+    }
+    modules
+}
+
+/// 設定ファイルの内容を検査する。`text` は読み込んだ設定ファイルの原文、`path` は問題の箇所の表示に使う。
+pub fn check_config(config: &Config, text: Option<&str>, path: &str) -> Vec<Diagnostic> {
+    let mut problems = text
+        .map(|text| check_module_keys(text, path))
+        .unwrap_or_default();
+    for (location, command) in config_modules(config) {
+        problems.extend(check_command(
+            command,
+            &format!("{}: {}", path, location),
+            Source::Config,
+            &config.palette,
+        ));
+    }
+
+    if let Some(theme) = &config.theme {
+        problems.extend(check_theme(theme, &format!("{}: theme", path)));
+    }
+    if let Some(ratio) = config.max_width
+        && !(0.0..=1.0).contains(&ratio)
+    {
+        problems.push(Diagnostic {
+            location: format!("{}: max_width", path),
+            message: format!("{} is outside 0.0-1.0", ratio),
+            suggestion: Some("use a fraction of the terminal width, e.g. 0.5".to_string()),
+        });
+    }
+    for name in config.timeout.modules.keys() {
+        if MODULE_NAMES.contains(&name.as_str()) {
+            continue;
+        }
+        problems.push(Diagnostic {
+            location: format!("{}: timeout.{}", path, name),
+            message: format!("Unknown module `{}`", name),
+            suggestion: Some(match closest(name, MODULE_NAMES.iter().copied()) {
+                Some(module) => format!("did you mean `{}`?", module),
+                None => format!("available modules: default_ms, {}", MODULE_NAMES.join(", ")),
+            }),
+        });
+    }
+    problems
+}
+
+/// 設定ファイル中の書式のうち、解釈できずに既定の書式で描画されるもの。
+/// 描画のたびにモジュールごとに報告しないよう、設定を読み込んだときに1度だけ検査する。
+pub fn check_config_formats(config: &Config, path: &str) -> Vec<Diagnostic> {
+    config_modules(config)
+        .into_iter()
+        .filter_map(|(location, command)| {
+            let format = command.segment_options().format.as_deref()?;
+            let e = Template::parse(format).err()?;
+            Some(format_error(
+                e,
+                format!("{}: {}.{}.format", path, location, command.name()),
+            ))
+        })
+        .collect()
+}
+
+// モジュールのオプションは `#[serde(flatten)]` で受け取るため、綴りを誤ったキーは
+// 読み込み時に黙って無視される。原文のキーを各モジュールのオプションと照らし合わせる。
+fn check_module_keys(text: &str, path: &str) -> Vec<Diagnostic> {
+    let Ok(table) = text.parse::<toml::Table>() else {
+        return Vec::new();
+    };
+    let modules = |value: Option<&toml::Value>, location: String| -> Vec<(String, toml::Value)> {
+        let Some(array) = value.and_then(toml::Value::as_array) else {
+            return Vec::new();
+        };
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, module)| (format!("{}[{}]", location, i), module.clone()))
+            .collect()
+    };
+    let mut entries = modules(table.get("modules"), "modules".to_string());
+    entries.extend(modules(
+        table.get("right_modules"),
+        "right_modules".to_string(),
+    ));
+    if let Some(lines) = table.get("lines").and_then(toml::Value::as_array) {
+        for (i, line) in lines.iter().enumerate() {
+            for side in ["left", "right"] {
+                entries.extend(modules(line.get(side), format!("lines[{}].{}", i, side)));
+            }
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (location, module) in entries {
+        let Some(module) = module.as_table() else {
+            continue;
+        };
+        for (name, options) in module {
+            // 未知のモジュール名は読み込み時にエラーになっている
+            let (Some(keys), Some(options)) = (Commands::config_keys(name), options.as_table())
+            else {
+                continue;
+            };
+            for key in options.keys().filter(|key| !keys.contains(key)) {
+                problems.push(Diagnostic {
+                    location: format!("{}: {}.{}.{}", path, location, name, key),
+                    message: format!("Unknown option `{}` (ignored)", key),
+                    suggestion: Some(match closest(key, keys.iter().map(String::as_str)) {
+                        Some(known) => format!("did you mean `{}`?", known),
+                        None => format!("`zsh-prompts {} --help` lists the options", name),
+                    }),
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// テーマが読み込めるかを検査する。
+pub fn check_theme(name: &str, location: &str) -> Vec<Diagnostic> {
+    match Theme::load(name) {
+        Ok(_) => Vec::new(),
+        Err(e) => vec![Diagnostic {
+            location: location.to_string(),
+            message: e.to_string(),
+            suggestion: None,
+        }],
+    }
+}
+
+/// 設定に使われる環境変数を検査する。
+pub fn check_environment() -> Vec<Diagnostic> {
+    match std::env::var(ICONS_ENV_VAR) {
+        Ok(value) if IconSet::from_env().is_none() => vec![Diagnostic {
+            location: format!("${}", ICONS_ENV_VAR),
+            message: format!("Unknown icon set `{}`", value),
+            suggestion: Some("use one of nerd, unicode, ascii".to_string()),
+        }],
+        _ => Vec::new(),
+    }
+}
+
+fn color_suggestion(e: &ColorParseError, palette: &Palette) -> Option<String> {
+    let suggestion = match e {
+        ColorParseError::UnknownName(name) => {
+            match closest(
+                &name.to_lowercase().replace('_', "-"),
+                NAMED_COLORS.iter().map(|(name, _)| *name),
+            ) {
+                Some(color) => format!("did you mean `{}`?", color),
+                None => {
+                    "use a color name, 0-255, #rrggbb, rgb(r, g, b) or hsl(h, s%, l%)".to_string()
+                }
+            }
+        }
+        ColorParseError::UnknownPaletteColor(name) => {
+            match closest(name, palette.keys().map(String::as_str)) {
+                Some(color) => format!("did you mean `@{}`?", color),
+                None => "define it in the [palette] table of the config file".to_string(),
+            }
+        }
+        _ => return None,
+    };
+    Some(suggestion)
+}
+
+// 綴りの近い候補。編集距離が名前の長さの1/3 (最低2) 以下のものだけを返す。
+pub(crate) fn closest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(2);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != cb))
+                .min(above + 1)
+                .min(row[j] + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_config_reports_location_and_suggestion() {
+        let text = r#"
+max_width = 1.5
+
+[[modules]]
+pwd = { color = "grean", format = "{icon} {pth}" }
+
+[[lines]]
+left = [{ git = { format = "{branch", brnch_color_option = "red" } }]
+
+[timeout]
+gti = 100
+"#;
+        let config: Config = toml::from_str(text).unwrap();
+        let problems = check_config(&config, Some(text), "config.toml");
+        let found: Vec<(&str, Option<&str>)> = problems
+            .iter()
+            .map(|p| (p.location.as_str(), p.suggestion.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    "config.toml: lines[0].left[0].git.brnch_color_option",
+                    Some("did you mean `branch_color_option`?")
+                ),
+                (
+                    "config.toml: modules[0].pwd.color",
+                    Some("did you mean `green`?")
+                ),
+                (
+                    "config.toml: modules[0].pwd.format",
+                    Some("did you mean `path`?")
+                ),
+                (
+                    "config.toml: lines[0].left[0].git.format",
+                    Some("write `{{` and `}}` for literal braces")
+                ),
+                (
+                    "config.toml: max_width",
+                    Some("use a fraction of the terminal width, e.g. 0.5")
+                ),
+                ("config.toml: timeout.gti", Some("did you mean `git`?")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_config_formats_reports_only_unparsable_formats() {
+        let config: Config = toml::from_str(
+            r#"
+[[modules]]
+pwd = { format = "{icon} {pth}" }
+
+[[right_modules]]
+time = { format = "{time" }
+"#,
+        )
+        .unwrap();
+        let problems = check_config_formats(&config, "config.toml");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].location,
+            "config.toml: right_modules[0].time.format"
+        );
+    }
+
+    #[test]
+    fn test_check_command_line_modules() {
+        let commands = Commands::parse_list([
+            "time", "--color", "@accnt", "::", "os", "--color", "#12", "::", "git", "--bg",
+            "@accnt",
+        ])
+        .unwrap();
+        let mut palette = Palette::new();
+        palette.insert("accent".to_string(), Color::Red);
+        let problems: Vec<Diagnostic> = commands
+            .iter()
+            .flat_map(|command| check_command(command, "prompt", Source::CommandLine, &palette))
+            .collect();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0].location, "prompt time --color");
+        assert_eq!(
+            problems[0].suggestion.as_deref(),
+            Some("did you mean `@accent`?")
+        );
+        assert_eq!(
+            problems[1].to_string(),
+            "prompt os --color: Invalid hex color (expected #rgb or #rrggbb): #12"
+        );
+        assert_eq!(problems[2].location, "prompt git --bg");
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("grean", "green"), 1);
+        assert_eq!(edit_distance("bleu", "blue"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(closest("xyz", ["red", "blue"]), None);
+    }
+}
@@ -0,0 +1,446 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::init::{START_ENV_VAR, STATUS_ENV_VAR};
+use crate::modules::{ColorDepth, IconSet, OutputMode, Palette};
+use crate::{Commands, LayoutLine, TimeBudget};
+use serde::{Deserialize, Serialize};
+
+/// 設定ファイルの場所を上書きするための環境変数
+pub const CONFIG_ENV_VAR: &str = "ZSH_PROMPTS_CONFIG";
+
+/// `~/.config/zsh-prompts/config.toml` に書かれる設定。
+/// CLIで指定された値はこのファイルの値より優先される。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 出力形式 (`raw` / `zsh` / `json`)
+    pub output: Option<OutputMode>,
+    /// 端末の色数 (`auto` / `truecolor` / `256` / `16`)
+    pub color_depth: Option<ColorDepth>,
+    /// モジュールの色を決めるテーマの名前、またはテーマファイルのパス
+    pub theme: Option<String>,
+    /// アイコンの種類 (`nerd` / `unicode` / `ascii`)。環境変数 `ZSH_PROMPTS_ICONS` が優先される。
+    pub icons: Option<IconSet>,
+    /// 色の指定で `@name` として参照できる名前付きの色。名前には英数字、`-`、`_` が使える。
+    pub palette: Palette,
+    /// `prompt` サブコマンドで表示するモジュールとその順序
+    pub modules: Vec<Commands>,
+    /// `prompt --right` (RPROMPT) で表示するモジュールとその順序
+    pub right_modules: Vec<Commands>,
+    /// 複数行のレイアウト。指定すると `modules` / `right_modules` の代わりに使われる。
+    pub lines: Vec<LayoutLine>,
+    /// プロンプトが使ってよい端末の幅の割合 (0.0-1.0)。超える場合は優先度の低いセグメントから削る。
+    pub max_width: Option<f64>,
+    /// モジュールごとの制限時間
+    pub timeout: TimeBudget,
+    /// 設定に誤りがあれば描画せずに終了する (`--strict` と同じ)
+    pub strict: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            output: None,
+            color_depth: None,
+            theme: None,
+            icons: None,
+            palette: Palette::new(),
+            modules: vec![
+                Commands::Os {
+                    color: None,
+                    segment: Default::default(),
+                },
+                Commands::Pwd {
+                    color: None,
+                    segment: Default::default(),
+                },
+                Commands::Git {
+                    options: Default::default(),
+                    path: None,
+                    segment: Default::default(),
+                },
+            ],
+            right_modules: vec![
+                Commands::Cmd {
+                    last_status: STATUS_ENV_VAR.to_string(),
+                    last_command_executed: Some(START_ENV_VAR.to_string()),
+                    color: None,
+                    success_color: None,
+                    failure_color: None,
+                    segment: Default::default(),
+                },
+                Commands::Time {
+                    color: None,
+                    segment: Default::default(),
+                },
+            ],
+            lines: Vec::new(),
+            max_width: None,
+            timeout: TimeBudget::default(),
+            strict: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// 読み込めずに除いた項目 (`modules[1]` など) とその理由
+    Entry(PathBuf, String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Entry(path, key, message) => {
+                write!(f, "{}: {}: {}", path.display(), key, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// 環境変数 `ZSH_PROMPTS_CONFIG`、`$XDG_CONFIG_HOME`、`~/.config` の順に設定ファイルの場所を決める。
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os(CONFIG_ENV_VAR).filter(|p| !p.is_empty()) {
+            return Some(PathBuf::from(path));
+        }
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+        Some(config_home.join("zsh-prompts").join("config.toml"))
+    }
+
+    /// 設定ファイルを読み込む。ファイルが存在しなければデフォルト値を返す。
+    /// 値の誤った項目があれば、その項目だけを除いて読み込み、除いた項目のエラーを合わせて返す。
+    pub fn load() -> Result<(Config, Vec<ConfigError>), ConfigError> {
+        match Self::path() {
+            Some(path) => Self::load_from(path),
+            None => Ok((Config::default(), Vec::new())),
+        }
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<(Config, Vec<ConfigError>), ConfigError> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok((Config::default(), Vec::new()));
+            }
+            Err(e) => return Err(ConfigError::Io(path, e)),
+        };
+        // TOML として読めなければ全体を諦める
+        let table: toml::Table =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+        match toml::from_str(&text) {
+            Ok(config) => Ok((config, Vec::new())),
+            Err(_) => Ok(Self::recover(table, &path)),
+        }
+    }
+
+    // 項目ごとに読み込めるかを確かめ、読めない項目を除いた設定を作る。
+    // モジュールの一覧は1つずつ確かめ、読めないオプションやモジュールだけを除く。
+    fn recover(table: toml::Table, path: &Path) -> (Config, Vec<ConfigError>) {
+        let mut errors = Vec::new();
+        let mut kept = toml::Table::new();
+        for (key, value) in table {
+            let value = match (key.as_str(), value) {
+                ("modules" | "right_modules", toml::Value::Array(modules)) => {
+                    let wrap = |module| entry(&key, toml::Value::Array(vec![module]));
+                    toml::Value::Array(valid_modules(modules, &key, path, &mut errors, wrap))
+                }
+                ("lines", toml::Value::Array(lines)) => {
+                    let mut checked = Vec::new();
+                    for (i, mut line) in lines.into_iter().enumerate() {
+                        let Some(table) = line.as_table_mut() else {
+                            checked.push(line);
+                            continue;
+                        };
+                        for side in ["left", "right"] {
+                            let Some(toml::Value::Array(modules)) = table.remove(side) else {
+                                continue;
+                            };
+                            let wrap = |module| {
+                                let line = entry(side, toml::Value::Array(vec![module]));
+                                entry("lines", toml::Value::Array(vec![line.into()]))
+                            };
+                            let location = format!("lines[{}].{}", i, side);
+                            let modules =
+                                valid_modules(modules, &location, path, &mut errors, wrap);
+                            table.insert(side.to_string(), toml::Value::Array(modules));
+                        }
+                        checked.push(line);
+                    }
+                    let wrap = |line| entry("lines", toml::Value::Array(vec![line]));
+                    toml::Value::Array(valid_items(checked, "lines", path, &mut errors, wrap))
+                }
+                (_, value) => value,
+            };
+            if is_valid(entry(&key, value.clone()), &key, path, &mut errors) {
+                kept.insert(key, value);
+            }
+        }
+        let config = toml::Value::Table(kept).try_into().unwrap_or_default();
+        (config, errors)
+    }
+
+    /// CLIで指定されたモジュールに、設定ファイル中の同じ種類のモジュールの値を補う。
+    pub fn apply_to(&self, command: Commands) -> Commands {
+        match self
+            .modules
+            .iter()
+            .chain(&self.right_modules)
+            .chain(
+                self.lines
+                    .iter()
+                    .flat_map(|line| line.left.iter().chain(&line.right)),
+            )
+            .find(|base| std::mem::discriminant(*base) == std::mem::discriminant(&command))
+        {
+            Some(base) => command.merged_with(base),
+            None => command,
+        }
+    }
+}
+
+// `key = value` だけを書いた設定
+fn entry(key: &str, value: toml::Value) -> toml::Table {
+    toml::Table::from_iter([(key.to_string(), value)])
+}
+
+// `entry` だけを書いた設定として読み込めるかを確かめ、読めなければ `location` と一緒にエラーを記録する
+fn is_valid(
+    entry: toml::Table,
+    location: &str,
+    path: &Path,
+    errors: &mut Vec<ConfigError>,
+) -> bool {
+    match toml::Value::Table(entry).try_into::<Config>() {
+        Ok(_) => true,
+        Err(e) => {
+            errors.push(ConfigError::Entry(
+                path.to_path_buf(),
+                location.to_string(),
+                e.message().trim_end().to_string(),
+            ));
+            false
+        }
+    }
+}
+
+// 一覧の要素を1つずつ確かめ、読み込めるものだけを残す。`wrap` は要素1つだけの設定を作る。
+fn valid_items(
+    items: Vec<toml::Value>,
+    location: &str,
+    path: &Path,
+    errors: &mut Vec<ConfigError>,
+    wrap: impl Fn(toml::Value) -> toml::Table,
+) -> Vec<toml::Value> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, item)| {
+            let location = format!("{}[{}]", location, i);
+            is_valid(wrap(item.clone()), &location, path, errors)
+        })
+        .map(|(_, item)| item)
+        .collect()
+}
+
+// モジュールを1つずつ確かめる。読めないモジュールは誤ったオプションだけを除き、
+// それでも読めなければモジュールごと除く。
+fn valid_modules(
+    modules: Vec<toml::Value>,
+    location: &str,
+    path: &Path,
+    errors: &mut Vec<ConfigError>,
+    wrap: impl Fn(toml::Value) -> toml::Table,
+) -> Vec<toml::Value> {
+    let parses = |module: &toml::Value| {
+        toml::Value::Table(wrap(module.clone()))
+            .try_into::<Config>()
+            .is_ok()
+    };
+    let mut kept = Vec::new();
+    for (i, module) in modules.into_iter().enumerate() {
+        let location = format!("{}[{}]", location, i);
+        if parses(&module) {
+            kept.push(module);
+            continue;
+        }
+        if let Some((name, toml::Value::Table(options))) = module
+            .as_table()
+            .filter(|module| module.len() == 1)
+            .and_then(|module| module.iter().next())
+        {
+            let mut valid_options = toml::Table::new();
+            for (key, value) in options {
+                let single = entry(name, toml::Value::Table(entry(key, value.clone())));
+                // 必須のオプションが無いことによるエラーは、このオプションの誤りではない
+                match toml::Value::Table(wrap(single.into())).try_into::<Config>() {
+                    Err(e) if !e.message().starts_with("missing field") => {
+                        let location = format!("{}.{}.{}", location, name, key);
+                        errors.push(ConfigError::Entry(
+                            path.to_path_buf(),
+                            location,
+                            e.message().trim_end().to_string(),
+                        ));
+                    }
+                    _ => {
+                        valid_options.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            let repaired = toml::Value::Table(entry(name, toml::Value::Table(valid_options)));
+            if parses(&repaired) {
+                kept.push(repaired);
+                continue;
+            }
+        }
+        is_valid(wrap(module), &location, path, errors);
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::Color;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config: Config = toml::from_str(
+            r##"
+output = "zsh"
+color_depth = "256"
+icons = "unicode"
+
+[[modules]]
+os = { color = "white", icons = "ascii" }
+
+[[modules]]
+[modules.git]
+branch_color_option = "yellow"
+staged_color_option = "#00ff00"
+
+[[modules]]
+cmd = { last_status = "STATUS" }
+
+[palette]
+accent = "rgb(255, 128, 0)"
+"##,
+        )
+        .unwrap();
+
+        assert_eq!(config.output, Some(OutputMode::Zsh));
+        assert_eq!(config.color_depth, Some(ColorDepth::Ansi256));
+        assert_eq!(config.icons, Some(IconSet::Unicode));
+        assert_eq!(
+            config.modules[0].segment_options().icons,
+            Some(IconSet::Ascii)
+        );
+        assert_eq!(config.palette["accent"], Color::Rgb(255, 128, 0));
+        assert_eq!(config.modules.len(), 3);
+        assert!(matches!(
+            &config.modules[1],
+            Commands::Git { options, path: None, .. }
+                if options.branch_color_option == Some(Color::Yellow)
+                    && options.staged_color_option == Some(Color::Rgb(0, 255, 0))
+        ));
+    }
+
+    #[test]
+    fn test_cli_values_override_config_values() {
+        let config: Config = toml::from_str(
+            r#"
+[[modules]]
+[modules.git]
+branch_color_option = "yellow"
+ahead_color_option = "blue"
+"#,
+        )
+        .unwrap();
+
+        let cli = Commands::parse_list(["git", "--branch-color-option", "red"]).unwrap();
+        let Commands::Git { options, .. } = config.apply_to(cli[0].clone()) else {
+            panic!("expected git module");
+        };
+        assert_eq!(options.branch_color_option, Some(Color::Red));
+        assert_eq!(options.ahead_color_option, Some(Color::Blue));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("modlues = []").is_err());
+    }
+
+    #[test]
+    fn test_invalid_entries_are_dropped_and_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+theme = 3
+output = "zsh"
+
+[[modules]]
+pwd = { color = "cyan" }
+[[modules]]
+git = { branch_color_option = "grean", staged_color_option = "red" }
+[[modules]]
+cmd = { last_status = "STATUS", success_color = "nope" }
+[[modules]]
+nosuch = {}
+
+[[lines]]
+left = [{ time = { bg = "blu" } }, { os = {} }]
+"#,
+        )
+        .unwrap();
+
+        let (config, errors) = Config::load_from(path).unwrap();
+        // 誤った項目以外はそのまま使われる
+        assert_eq!(config.output, Some(OutputMode::Zsh));
+        assert_eq!(config.theme, None);
+        assert_eq!(config.modules.len(), 3);
+        assert!(matches!(
+            &config.modules[1],
+            Commands::Git { options, .. }
+                if options.branch_color_option.is_none()
+                    && options.staged_color_option == Some(Color::Red)
+        ));
+        assert!(matches!(
+            &config.modules[2],
+            Commands::Cmd { last_status, success_color: None, .. } if last_status == "STATUS"
+        ));
+        assert_eq!(config.lines[0].left.len(), 2);
+
+        let locations: Vec<&str> = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Entry(_, location, _) => location.as_str(),
+                _ => panic!("unexpected error: {}", e),
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                "lines[0].left[0].time.bg",
+                "modules[1].git.branch_color_option",
+                "modules[2].cmd.success_color",
+                "modules[3]",
+                "theme",
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::modules::{Context, PromptSegment};
+use crate::{Commands, TimeBudget};
+use rkyv::rancor;
+use rkyv::util::AlignedVec;
+
+/// クライアントがデーモンの応答を待つ最大時間
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// 1メッセージの最大サイズ。これを超える長さのフレームは不正として扱う。
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// フレームの形式。リクエストや応答に含まれる型 (`Commands`、`PromptSegment`、
+/// `SegmentOptions`、`Context` など) のアーカイブの形が変わったら必ず上げる。
+/// 異なる版のフレームはアーカイブを読まずに捨てるので、古いデーモンが別の形として解釈することは無い。
+pub const PROTOCOL_VERSION: u32 = 5;
+/// プロセス全体に効くため、リクエストごとに切り替えられない環境変数。
+/// クライアントとデーモンで値が違えば、デーモンはリクエストを処理しない。
+const PROCESS_WIDE_VARS: &[&str] = &["TZ", "HOME", "XDG_CONFIG_HOME"];
+
+/// クライアントからデーモンへ送るリクエスト。
+/// モジュールはクライアントのカレントディレクトリと環境変数の下で評価される。
+#[derive(Debug, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+pub struct DaemonRequest {
+    pub context: Context,
+    pub commands: Vec<Commands>,
+    pub budget: TimeBudget,
+}
+
+impl DaemonRequest {
+    pub fn new(commands: &[Commands], budget: &TimeBudget, context: &Context) -> Self {
+        Self {
+            context: context.clone(),
+            commands: commands.to_vec(),
+            budget: budget.clone(),
+        }
+    }
+}
+
+/// ユーザーごとのソケットのパス。`$XDG_RUNTIME_DIR` が無ければ一時ディレクトリを使う。
+pub fn socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR").filter(|p| !p.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("zsh-prompts.sock"),
+        None => env::temp_dir().join(format!("zsh-prompts-{}.sock", current_uid())),
+    }
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: getuid は常に成功し、副作用を持たない
+    unsafe { libc::getuid() }
+}
+
+// 接続してきたプロセスのユーザー
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` と `len` は SO_PEERCRED が書き込む大きさの有効な領域を指す
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `uid` と `gid` は getpeereid が書き込む有効な領域を指す
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+// ソケットファイルを作る間だけ umask を 0077 にし、他のユーザーが接続できる時間を作らない
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    // SAFETY: umask は常に成功する。待ち受けを始める前なので他のスレッドはファイルを作っていない。
+    let previous = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    // SAFETY: 同上
+    unsafe { libc::umask(previous) };
+    listener
+}
+
+// --- フレームの読み書き (u32 LEの形式の版 + u32 LEの長さ + rkyvでアーカイブしたバイト列) ---
+fn write_frame(stream: &mut UnixStream, bytes: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(bytes)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<AlignedVec> {
+    let mut version_bytes = [0u8; 4];
+    stream.read_exact(&mut version_bytes)?;
+    let version = u32::from_le_bytes(version_bytes);
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "protocol version {} is not supported (expected {})",
+                version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    // rkyvのアーカイブはアラインされたバッファ上で検証する必要がある
+    let mut aligned = AlignedVec::with_capacity(len);
+    aligned.extend_from_slice(&buf);
+    Ok(aligned)
+}
+
+fn invalid_data(e: rancor::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// デーモンにモジュールの評価を依頼し、モジュールごとのセグメントを受け取る。
+/// デーモンが起動していない、または応答できない場合は `None` を返すので、
+/// 呼び出し側はプロセス内での評価にフォールバックする。
+pub fn request(
+    commands: &[Commands],
+    budget: &TimeBudget,
+    context: &Context,
+) -> Option<Vec<Vec<PromptSegment>>> {
+    request_to(&socket_path(), commands, budget, context)
+}
+
+fn request_to(
+    path: &Path,
+    commands: &[Commands],
+    budget: &TimeBudget,
+    context: &Context,
+) -> Option<Vec<Vec<PromptSegment>>> {
+    // 他のユーザーが用意したソケットには接続しない
+    if fs::metadata(path).ok()?.uid() != current_uid() {
+        return None;
+    }
+    let mut stream = UnixStream::connect(path).ok()?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+
+    let request = DaemonRequest::new(commands, budget, context);
+    let bytes = rkyv::to_bytes::<rancor::Error>(&request).ok()?;
+    write_frame(&mut stream, &bytes).ok()?;
+
+    let response = read_frame(&mut stream).ok()?;
+    // アーカイブは検証してから復元する
+    rkyv::from_bytes::<Vec<Vec<PromptSegment>>, rancor::Error>(&response).ok()
+}
+
+/// ソケットで待ち受け、リクエストを処理し続ける。
+/// 応答の遅い接続が他のシェルを待たせないよう、接続ごとにスレッドを分ける。
+pub fn run(path: &Path) -> io::Result<()> {
+    prepare_socket_path(path)?;
+    let listener = bind_private(path)?;
+    eprintln!("zsh-prompts daemon listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = handle(&mut stream) {
+                        eprintln!("Error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// 前回のデーモンが残したソケットファイルを片付ける。
+// 既に別のデーモンが応答している場合はエラーにする。
+fn prepare_socket_path(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another daemon is already running",
+        ));
+    }
+    fs::remove_file(path)
+}
+
+fn handle(stream: &mut UnixStream) -> io::Result<()> {
+    // リクエストには `sh -c` で実行するコマンドが含まれるので、同じユーザーからの接続だけを受け付ける
+    let uid = peer_uid(stream)?;
+    if uid != current_uid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("rejected a connection from uid {}", uid),
+        ));
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // 形式の版が違うフレームはエラーになり、応答せずに切断するのでクライアント側での評価に任せる
+    let frame = read_frame(stream)?;
+    let request = rkyv::from_bytes::<DaemonRequest, rancor::Error>(&frame).map_err(invalid_data)?;
+    if !shares_process_environment(&request.context) {
+        return Ok(());
+    }
+
+    // プロセスのカレントディレクトリや環境変数は書き換えず、クライアントのものを各モジュールに渡す。
+    // 前のリクエストで制限時間を過ぎたモジュールのスレッドがまだ動いていても影響しない。
+    let segments = Commands::exec_grouped(&request.commands, &request.budget, &request.context);
+
+    let bytes = rkyv::to_bytes::<rancor::Error>(&segments).map_err(invalid_data)?;
+    write_frame(stream, &bytes)
+}
+
+// タイムゾーンや git の設定ファイルの場所などはプロセスの環境変数から決まるため、クライアントと同じときだけ評価する
+fn shares_process_environment(context: &Context) -> bool {
+    PROCESS_WIDE_VARS
+        .iter()
+        .all(|name| context.var(name) == env::var(name).ok().as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::Color;
+
+    #[test]
+    fn test_request_and_response_roundtrip_through_archive() {
+        let commands =
+            Commands::parse_list(["pwd", "::", "git", "--branch-color-option", "red"]).unwrap();
+        let request = DaemonRequest::new(&commands, &TimeBudget::default(), &Context::current());
+        let bytes = rkyv::to_bytes::<rancor::Error>(&request).unwrap();
+        let decoded = rkyv::from_bytes::<DaemonRequest, rancor::Error>(&bytes).unwrap();
+        assert_eq!(decoded.commands.len(), 2);
+        assert_eq!(decoded.context.cwd, request.context.cwd);
+        assert_eq!(decoded.context.vars, request.context.vars);
+        assert_eq!(decoded.context.cache_dir, request.context.cache_dir);
+
+        let segments = vec![vec![PromptSegment::new_with_color(
+            "main".to_string(),
+            "red",
+        )]];
+        let bytes = rkyv::to_bytes::<rancor::Error>(&segments).unwrap();
+        let decoded = rkyv::from_bytes::<Vec<Vec<PromptSegment>>, rancor::Error>(&bytes).unwrap();
+        assert_eq!(decoded[0][0].content, "main");
+        assert_eq!(decoded[0][0].color, Some(Color::Red));
+    }
+
+    #[test]
+    fn test_frames_from_another_protocol_version_are_rejected() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        write_frame(&mut client, b"payload").unwrap();
+        assert_eq!(&read_frame(&mut server).unwrap()[..], b"payload");
+
+        client
+            .write_all(&(PROTOCOL_VERSION + 1).to_le_bytes())
+            .unwrap();
+        client.write_all(&7u32.to_le_bytes()).unwrap();
+        client.write_all(b"payload").unwrap();
+        let e = read_frame(&mut server).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_stalled_connection_does_not_block_other_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.sock");
+        let server_path = path.clone();
+        thread::spawn(move || run(&server_path));
+        while UnixStream::connect(&path).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // 何も送らない接続があっても、次のクライアントはすぐに応答を受け取る
+        let _stalled = UnixStream::connect(&path).unwrap();
+        let commands = Commands::parse_list(["pwd"]).unwrap();
+        let started = std::time::Instant::now();
+        let response = request_to(
+            &path,
+            &commands,
+            &TimeBudget::default(),
+            &Context::current(),
+        );
+        assert_eq!(response.map(|groups| groups.len()), Some(1));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // ソケットは作られた時点で所有者だけが読み書きできる
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+    }
+
+    #[test]
+    fn test_peer_uid_is_the_connecting_user() {
+        let (client, _server) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&client).unwrap(), current_uid());
+    }
+}
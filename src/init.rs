@@ -0,0 +1,53 @@
+use std::env;
+
+use clap::ValueEnum;
+
+/// `cmd` モジュールが終了ステータスを読み取る環境変数
+pub const STATUS_ENV_VAR: &str = "ZSH_PROMPTS_STATUS";
+/// `cmd` モジュールがコマンド開始時刻 (`$EPOCHREALTIME`) を読み取る環境変数
+pub const START_ENV_VAR: &str = "ZSH_PROMPTS_START";
+
+const ZSH_INIT_SCRIPT: &str = include_str!("init.zsh");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Zsh,
+}
+
+/// `eval "$(zsh-prompts init zsh)"` で読み込むフック定義を生成する。
+pub fn init_script(shell: Shell) -> String {
+    let bin = env::current_exe()
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "zsh-prompts".to_string());
+
+    match shell {
+        Shell::Zsh => ZSH_INIT_SCRIPT
+            .replace("::BIN::", &single_quote(&bin))
+            .replace("::STATUS_VAR::", STATUS_ENV_VAR)
+            .replace("::START_VAR::", START_ENV_VAR),
+    }
+}
+
+// シェルの単一引用符で囲む。内部の `'` は `'\''` に置き換える。
+fn single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zsh_init_script_has_no_placeholders() {
+        let script = init_script(Shell::Zsh);
+        assert!(!script.contains("::"));
+        assert!(script.contains("add-zsh-hook precmd _zsh_prompts_precmd"));
+        assert!(script.contains(&format!("{}=$last_status", STATUS_ENV_VAR)));
+        assert!(script.contains("--output zsh prompt --both 2>/dev/null"));
+    }
+
+    #[test]
+    fn test_single_quote_escapes_quotes() {
+        assert_eq!(single_quote("/opt/it's/bin"), r"'/opt/it'\''s/bin'");
+    }
+}
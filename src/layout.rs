@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use unicode_width::UnicodeWidthChar;
+
+use crate::Commands;
+use crate::modules::{
+    ColorDepth, OutputMode, PromptSegment, escape_for_zsh, sanitize_untrusted, take_escape_sequence,
+};
+use crate::render::render;
+
+/// `$COLUMNS` が分からないときに使う端末の桁数
+pub const DEFAULT_COLUMNS: usize = 80;
+
+/// 複数行レイアウトの1行。左寄せと右寄せのモジュールの間を `fill` で埋めて端末の幅に揃える。
+/// 最後の行は入力行になるので、その `right` は RPROMPT として表示される。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutLine {
+    pub left: Vec<Commands>,
+    pub right: Vec<Commands>,
+    /// 左右の間を埋める文字列 (未指定なら空白)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill: Option<String>,
+}
+
+/// 環境変数 `COLUMNS` から端末の桁数を得る。
+pub fn terminal_columns() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+/// 端末上での表示幅。エスケープシーケンスと制御文字は幅0、CJKや絵文字などの全角文字は幅2として数える。
+/// Nerd Fontのアイコン (私用領域) は zsh の wcwidth と同じく幅1になる。
+pub fn display_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut skipped = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            take_escape_sequence(&mut chars, &mut skipped);
+            skipped.clear();
+        } else {
+            width += c.width().unwrap_or(0);
+        }
+    }
+    width
+}
+
+// エスケープを含まない出力で幅を測る。zsh向けの `%%` などは表示上1文字なので数えない。
+fn measured_width(segments: &[PromptSegment], depth: ColorDepth) -> usize {
+    display_width(&render(segments, OutputMode::Raw, depth))
+}
+
+/// 左右のセグメントを1行にまとめ、間を `fill` で埋めて幅を `columns` に揃える。
+/// 右側が収まらない場合は左側だけを出力する。
+pub fn render_line(
+    left: &[PromptSegment],
+    right: &[PromptSegment],
+    fill: &str,
+    columns: usize,
+    mode: OutputMode,
+    depth: ColorDepth,
+) -> String {
+    let left_width = measured_width(left, depth);
+    let right_width = measured_width(right, depth);
+    // 左右がくっつかないよう、右側がある場合は最低1桁を空ける
+    let min_gap = usize::from(!right.is_empty());
+    let Some(gap) = columns
+        .checked_sub(left_width + right_width)
+        .filter(|&gap| gap >= min_gap)
+    else {
+        return render(left, mode, depth);
+    };
+
+    let fill = sanitize_untrusted(fill);
+    let fill_width = display_width(&fill);
+    // 幅2の文字などで割り切れない分は空白で補う
+    let mut filler = match fill_width {
+        0 => " ".repeat(gap),
+        width => fill.repeat(gap / width) + &" ".repeat(gap % width),
+    };
+    if mode == OutputMode::Zsh {
+        filler = escape_for_zsh(&filler);
+    }
+    format!(
+        "{}{}{}",
+        render(left, mode, depth),
+        filler,
+        render(right, mode, depth)
+    )
+}
+
+/// `left` と `right` を並べた幅が `max_width` に収まるまで、優先度の低いセグメントから
+/// 短い表示に置き換え、それでも足りなければ削除する。優先度が同じなら右にあるものから削る。
+pub fn fit_to_width(
+    left: &mut Vec<PromptSegment>,
+    right: &mut Vec<PromptSegment>,
+    max_width: usize,
+    depth: ColorDepth,
+) {
+    loop {
+        // 左右の両方がある場合は間に最低1桁を空ける
+        let gap = usize::from(!left.is_empty() && !right.is_empty());
+        let width = measured_width(left, depth) + measured_width(right, depth) + gap;
+        if width <= max_width {
+            return;
+        }
+        let Some((side, index)) = left
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| (0, i, segment.priority))
+            .chain(
+                right
+                    .iter()
+                    .enumerate()
+                    .map(|(i, segment)| (1, i, segment.priority)),
+            )
+            .min_by_key(|&(side, i, priority)| (priority, std::cmp::Reverse((side, i))))
+            .map(|(side, i, _)| (side, i))
+        else {
+            return;
+        };
+        let segments = if side == 0 { &mut *left } else { &mut *right };
+        match segments[index].short.take() {
+            Some(short) => segments[index].content = short,
+            None => {
+                // 書式で連結された次のセグメントが、削除したセグメントの位置から始まるようにする
+                let removed = segments.remove(index);
+                if let Some(next) = segments.get_mut(index) {
+                    next.glue &= removed.glue;
+                }
+            }
+        }
+    }
+}
+
+/// 端末の幅に対する割合 `ratio` から、プロンプトが使ってよい桁数を求める。
+pub fn width_budget(columns: usize, ratio: f64) -> usize {
+    (columns as f64 * ratio.clamp(0.0, 1.0)).floor() as usize
+}
+
+/// `prompt` (左) または `prompt --right` で評価するモジュールを、評価する順に並べる。
+/// 左は最後の行の右側以外の全て、右は最後の行の右側だけになる。
+pub fn layout_commands(lines: &[LayoutLine], right: bool) -> Vec<Commands> {
+    let Some((last, rest)) = lines.split_last() else {
+        return Vec::new();
+    };
+    if right {
+        return last.right.clone();
+    }
+    rest.iter()
+        .flat_map(|line| line.left.iter().chain(&line.right))
+        .chain(&last.left)
+        .cloned()
+        .collect()
+}
+
+/// `layout_commands` の順に評価したモジュールごとのセグメントから、複数行のプロンプトを組み立てる。
+/// 最後の行以外は `columns` の幅まで埋める。
+/// `max_width` を指定すると、各行をその幅に収まるよう優先度の低いセグメントから削る。
+pub fn render_layout(
+    lines: &[LayoutLine],
+    groups: &[Vec<PromptSegment>],
+    right: bool,
+    columns: usize,
+    max_width: Option<usize>,
+    mode: OutputMode,
+    depth: ColorDepth,
+) -> String {
+    if right || mode == OutputMode::Json {
+        let mut segments = groups.concat();
+        if let Some(max_width) = max_width
+            && mode != OutputMode::Json
+        {
+            fit_to_width(&mut segments, &mut Vec::new(), max_width, depth.resolve());
+        }
+        return render(&segments, mode, depth);
+    }
+    render_layout_both(lines, groups, columns, max_width, mode, depth).0
+}
+
+/// 左右のプロンプトをまとめて組み立てる。`groups` は左、右の `layout_commands` の順に並べる。
+/// 最後の行の左側と右プロンプトは同じ行に表示されるので、合わせて `max_width` に収める。
+pub fn render_layout_both(
+    lines: &[LayoutLine],
+    groups: &[Vec<PromptSegment>],
+    columns: usize,
+    max_width: Option<usize>,
+    mode: OutputMode,
+    depth: ColorDepth,
+) -> (String, String) {
+    let fit = |left: &mut Vec<PromptSegment>, right: &mut Vec<PromptSegment>| {
+        if let Some(max_width) = max_width {
+            fit_to_width(left, right, max_width, depth.resolve());
+        }
+    };
+
+    let mut groups = groups.iter();
+    let mut take = |count: usize| -> Vec<PromptSegment> {
+        groups.by_ref().take(count).flatten().cloned().collect()
+    };
+    let Some((last, rest)) = lines.split_last() else {
+        return (String::new(), String::new());
+    };
+    let mut rows: Vec<String> = rest
+        .iter()
+        .map(|line| {
+            let mut left = take(line.left.len());
+            let mut right = take(line.right.len());
+            fit(&mut left, &mut right);
+            let fill = line.fill.as_deref().unwrap_or(" ");
+            render_line(&left, &right, fill, columns, mode, depth)
+        })
+        .collect();
+    let mut last_left = take(last.left.len());
+    let mut last_right = take(last.right.len());
+    fit(&mut last_left, &mut last_right);
+    rows.push(render(&last_left, mode, depth));
+    (rows.join("\n"), render(&last_right, mode, depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(content: &str) -> PromptSegment {
+        PromptSegment::new(content.to_string())
+    }
+
+    #[test]
+    fn test_display_width_ignores_escapes_and_counts_wide_chars() {
+        assert_eq!(display_width("\x1b[1;31mab\x1b[39m"), 2);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("\u{f418} main"), 6);
+        assert_eq!(display_width("\x1b]0;title\x07x"), 1);
+    }
+
+    #[test]
+    fn test_render_line_fills_to_columns() {
+        let line = render_line(
+            &[plain("~/src")],
+            &[plain("12:00")],
+            "─",
+            16,
+            OutputMode::Raw,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(line, "~/src──────12:00");
+        assert_eq!(display_width(&line), 16);
+
+        // 幅2の埋め文字で割り切れない分は空白になる
+        let line = render_line(
+            &[plain("a")],
+            &[plain("b")],
+            "＝",
+            9,
+            OutputMode::Raw,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(line, "a＝＝＝ b");
+    }
+
+    #[test]
+    fn test_render_line_drops_right_side_when_too_narrow() {
+        let line = render_line(
+            &[plain("long-directory")],
+            &[plain("12:00")],
+            " ",
+            18,
+            OutputMode::Raw,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(line, "long-directory");
+    }
+
+    #[test]
+    fn test_render_layout_splits_groups_into_lines() {
+        let config: crate::Config = toml::from_str(
+            r#"
+[[lines]]
+fill = "%"
+left = [{ pwd = {} }]
+right = [{ time = {} }]
+
+[[lines]]
+left = [{ os = {} }]
+right = [{ time = {} }]
+"#,
+        )
+        .unwrap();
+        assert_eq!(layout_commands(&config.lines, false).len(), 3);
+        assert_eq!(layout_commands(&config.lines, true).len(), 1);
+
+        let groups = vec![vec![plain("~")], vec![plain("1:00")], vec![plain(">")]];
+        let prompt = render_layout(
+            &config.lines,
+            &groups,
+            false,
+            8,
+            None,
+            OutputMode::Zsh,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(prompt, "~%%%%%%1:00\n>");
+
+        // 最後の行の左側と右プロンプトは、合わせて1つの幅に収める
+        let mut groups = groups;
+        groups.push(vec![plain("12:00:00").with_priority(10)]);
+        let (left, right) = render_layout_both(
+            &config.lines,
+            &groups,
+            8,
+            Some(8),
+            OutputMode::Zsh,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(left, "~%%%%%%1:00\n>");
+        assert_eq!(right, "");
+        let (_, right) = render_layout_both(
+            &config.lines,
+            &groups,
+            8,
+            Some(10),
+            OutputMode::Zsh,
+            ColorDepth::TrueColor,
+        );
+        assert_eq!(right, "12:00:00");
+    }
+
+    #[test]
+    fn test_fit_to_width_abbreviates_then_drops_lowest_priority() {
+        let mut left = vec![
+            plain("os").with_priority(10),
+            plain("~/src/project").with_short("…/project".to_string()),
+            plain("stash").with_priority(30),
+        ];
+        let mut right = vec![plain("12:00:00").with_priority(20)];
+
+        fit_to_width(&mut left, &mut right, 40, ColorDepth::TrueColor);
+        assert_eq!(left.len(), 3);
+        assert_eq!(right.len(), 1);
+
+        fit_to_width(&mut left, &mut right, 20, ColorDepth::TrueColor);
+        let contents: Vec<&str> = left.iter().map(|s| s.content.as_str()).collect();
+        assert_eq!(contents, ["~/src/project", "stash"]);
+        assert!(right.is_empty());
+
+        fit_to_width(&mut left, &mut right, 15, ColorDepth::TrueColor);
+        let contents: Vec<&str> = left.iter().map(|s| s.content.as_str()).collect();
+        assert_eq!(contents, ["~/src/project"]);
+
+        // 優先度の高いセグメントも、最後は短い表示に置き換えられる
+        fit_to_width(&mut left, &mut right, 10, ColorDepth::TrueColor);
+        assert_eq!(left[0].content, "…/project");
+        assert_eq!(width_budget(100, 0.25), 25);
+    }
+
+    #[test]
+    fn test_fit_to_width_unglues_after_removed_segment() {
+        let glued = |content: &str| PromptSegment {
+            glue: true,
+            ..plain(content)
+        };
+        let mut left = vec![plain("a"), plain("G ").with_priority(40), glued("main")];
+        fit_to_width(&mut left, &mut Vec::new(), 6, ColorDepth::TrueColor);
+        assert_eq!(
+            render(&left, OutputMode::Raw, ColorDepth::TrueColor),
+            "a main"
+        );
+    }
+}
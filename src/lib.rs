@@ -1,11 +1,34 @@
+mod check;
+mod config;
+#[cfg(unix)]
+pub mod daemon;
+mod init;
+mod layout;
 mod modules;
+mod profile;
+mod render;
+mod template;
+mod theme;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::modules::git::GitStatusOptions;
-use clap::Subcommand;
+pub use check::*;
+use clap::{CommandFactory, Parser, Subcommand};
+pub use config::*;
+pub use init::*;
+pub use layout::*;
 pub use modules::*;
+pub use profile::*;
+pub use render::*;
 pub use serde::Deserialize;
 pub use serde::Serialize;
+pub use template::*;
+pub use theme::*;
 
 #[derive(
     Subcommand,
@@ -17,30 +40,44 @@ pub use serde::Serialize;
     rkyv::Deserialize,
     rkyv::Archive,
 )]
+#[serde(rename_all = "lowercase")]
 pub enum Commands {
     /// Get OS icon
     Os {
         #[arg(long)]
         color: Option<String>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
     },
     /// Get current working directory info
     Pwd {
         #[arg(long)]
         color: Option<String>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
     },
     /// Get current time
     Time {
         #[arg(long)]
         color: Option<String>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
     },
     /// Get git status
     Git {
         #[command(flatten)]
-        options: GitStatusOptions,
+        #[serde(flatten)]
+        options: Box<GitStatusOptions>,
         #[arg(long)]
         #[rkyv(with = rkyv::with::Map<rkyv::with::AsString>)]
         #[serde(skip_serializing_if = "Option::is_none")]
         path: Option<PathBuf>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
     },
     /// Get last command execution info
     Cmd {
@@ -50,64 +87,977 @@ pub enum Commands {
         last_command_executed: Option<String>,
         #[arg(long)]
         color: Option<String>,
+        /// Color when the last command succeeded [default: green]
+        #[arg(long)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        success_color: Option<Color>,
+        /// Color when the last command failed [default: red]
+        #[arg(long)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        failure_color: Option<Color>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
     },
+    /// Show the first line printed by a command or stored in a file
+    ///
+    /// Nothing is shown when the output is empty.
+    Custom {
+        /// Shell command to run with `sh -c`
+        #[arg(long, conflicts_with = "file")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        command: Option<String>,
+        /// File to read instead of running a command
+        #[arg(long)]
+        #[rkyv(with = rkyv::with::Map<rkyv::with::AsString>)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file: Option<PathBuf>,
+        /// Kill the command after this many milliseconds (0 = unlimited) [default: 500]
+        #[arg(long, value_name = "MS")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+        /// Reuse the command's output for this many seconds [default: 0]
+        ///
+        /// The output is cached per command and working directory; environment variables
+        /// are not part of the key.
+        #[arg(long, value_name = "SECS")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_ttl: Option<u64>,
+        /// Color of the output [default: the terminal's default color]
+        #[arg(long)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        color: Option<Color>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
+    },
+    /// Show environment variables such as `AWS_PROFILE` or `VIRTUAL_ENV`
+    ///
+    /// Variables that are unset or empty are not shown.
+    Env {
+        /// Variable to show (can be repeated)
+        #[arg(long, value_name = "NAME")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        var: Vec<String>,
+        /// Color of the values [default: yellow]
+        #[arg(long)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        color: Option<Color>,
+        /// Color for values matching a regular expression, e.g. `prod|production=red`
+        /// (can be repeated; the first match wins)
+        #[arg(long, value_name = "PATTERN=COLOR")]
+        #[rkyv(with = rkyv::with::Map<env::AsValueColorString>)]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        value_color: Vec<env::ValueColor>,
+        /// Icon shown before every value [default: depends on the variable]
+        #[arg(long)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+        #[command(flatten)]
+        #[serde(flatten)]
+        segment: SegmentOptions,
+    },
+}
+
+/// 時間制限を設定しなかった場合の1モジュールあたりの制限時間
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+/// 同時に動かすモジュールのスレッドの上限。
+/// 制限時間を過ぎても終わらないスレッドが、長く動き続けるデーモンの中で際限なく増えないようにする。
+pub const MAX_MODULE_THREADS: usize = 64;
+
+static MODULE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// 動いているモジュールのスレッドを数える。スレッドが終わると (パニックしても) 解放される。
+struct ModuleThread;
+
+impl ModuleThread {
+    fn acquire() -> Option<ModuleThread> {
+        MODULE_THREADS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                (running < MAX_MODULE_THREADS).then_some(running + 1)
+            })
+            .ok()
+            .map(|_| ModuleThread)
+    }
+}
+
+impl Drop for ModuleThread {
+    fn drop(&mut self) {
+        MODULE_THREADS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// モジュールごとの制限時間 (ミリ秒)。0 は無制限を表す。
+///
+/// ```toml
+/// [timeout]
+/// default_ms = 500
+/// git = 2000
+/// ```
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
+)]
+pub struct TimeBudget {
+    pub default_ms: Option<u64>,
+    #[serde(flatten)]
+    pub modules: BTreeMap<String, u64>,
+}
+
+impl TimeBudget {
+    pub fn for_module(&self, name: &str) -> Option<Duration> {
+        let ms = self
+            .modules
+            .get(name)
+            .copied()
+            .or(self.default_ms)
+            .unwrap_or(DEFAULT_TIMEOUT_MS);
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+}
+
+/// モジュールの名前 (`Commands::name` の値)
+pub const MODULE_NAMES: &[&str] = &["os", "pwd", "time", "git", "cmd", "custom", "env"];
+
+/// `prompt` サブコマンドでモジュール同士を区切るトークン
+pub const MODULE_SEPARATOR: &str = "::";
+
+// 1モジュール分の引数を解析するためのラッパー
+#[derive(Parser, Debug)]
+#[command(name = "zsh-prompts prompt", no_binary_name = true)]
+struct ModuleArgs {
+    #[command(subcommand)]
+    command: Commands,
 }
+
 impl Commands {
-    pub fn exec(&self) -> Vec<PromptSegment> {
+    /// `os :: pwd :: git --branch-color-option red` のような引数列を
+    /// 順序を保ったままモジュールのリストに変換する。
+    pub fn parse_list<I, S>(args: I) -> Result<Vec<Commands>, clap::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut commands = Vec::new();
+        let mut chunk: Vec<String> = Vec::new();
+        for arg in args
+            .into_iter()
+            .map(Into::into)
+            .chain([MODULE_SEPARATOR.to_string()])
+        {
+            if arg == MODULE_SEPARATOR {
+                if !chunk.is_empty() {
+                    commands.push(ModuleArgs::try_parse_from(chunk.drain(..))?.command);
+                }
+            } else {
+                chunk.push(arg);
+            }
+        }
+        Ok(commands)
+    }
+
+    /// 設定ファイルでモジュール `name` に書けるキー。未知のモジュールなら `None`。
+    pub fn config_keys(name: &str) -> Option<Vec<String>> {
+        let command = ModuleArgs::command();
+        let module = command.find_subcommand(name)?;
+        let keys = module
+            .get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| id != "help")
+            // コマンドラインでは指定できない項目
+            .chain(["icons".to_string()])
+            .collect();
+        Some(keys)
+    }
+
+    /// 未指定のオプションを同じ種類のモジュール `base` の値で補う。
+    /// 種類が異なる場合は `self` をそのまま返す。
+    pub fn merged_with(self, base: &Commands) -> Commands {
+        match (self, base) {
+            (
+                Self::Os { color, segment },
+                Self::Os {
+                    color: base_color,
+                    segment: base_segment,
+                },
+            ) => Self::Os {
+                color: color.or_else(|| base_color.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (
+                Self::Pwd { color, segment },
+                Self::Pwd {
+                    color: base_color,
+                    segment: base_segment,
+                },
+            ) => Self::Pwd {
+                color: color.or_else(|| base_color.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (
+                Self::Time { color, segment },
+                Self::Time {
+                    color: base_color,
+                    segment: base_segment,
+                },
+            ) => Self::Time {
+                color: color.or_else(|| base_color.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (
+                Self::Git {
+                    options,
+                    path,
+                    segment,
+                },
+                Self::Git {
+                    options: base_options,
+                    path: base_path,
+                    segment: base_segment,
+                },
+            ) => Self::Git {
+                options: Box::new(options.merged_with(base_options)),
+                path: path.or_else(|| base_path.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (
+                Self::Cmd {
+                    last_status,
+                    last_command_executed,
+                    color,
+                    success_color,
+                    failure_color,
+                    segment,
+                },
+                Self::Cmd {
+                    last_command_executed: base_last_command_executed,
+                    color: base_color,
+                    success_color: base_success_color,
+                    failure_color: base_failure_color,
+                    segment: base_segment,
+                    ..
+                },
+            ) => Self::Cmd {
+                last_status,
+                last_command_executed: last_command_executed
+                    .or_else(|| base_last_command_executed.clone()),
+                color: color.or_else(|| base_color.clone()),
+                success_color: success_color.or_else(|| base_success_color.clone()),
+                failure_color: failure_color.or_else(|| base_failure_color.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (
+                Self::Custom {
+                    command,
+                    file,
+                    timeout,
+                    cache_ttl,
+                    color,
+                    segment,
+                },
+                Self::Custom {
+                    command: base_command,
+                    file: base_file,
+                    timeout: base_timeout,
+                    cache_ttl: base_cache_ttl,
+                    color: base_color,
+                    segment: base_segment,
+                },
+            ) => {
+                // コマンドとファイルはどちらか一方だけを使うので、組で補う
+                let (command, file) = if command.is_some() || file.is_some() {
+                    (command, file)
+                } else {
+                    (base_command.clone(), base_file.clone())
+                };
+                Self::Custom {
+                    command,
+                    file,
+                    timeout: timeout.or(*base_timeout),
+                    cache_ttl: cache_ttl.or(*base_cache_ttl),
+                    color: color.or_else(|| base_color.clone()),
+                    segment: segment.merged_with(base_segment),
+                }
+            }
+            (
+                Self::Env {
+                    var,
+                    color,
+                    value_color,
+                    icon,
+                    segment,
+                },
+                Self::Env {
+                    var: base_var,
+                    color: base_color,
+                    value_color: base_value_color,
+                    icon: base_icon,
+                    segment: base_segment,
+                },
+            ) => Self::Env {
+                var: if var.is_empty() {
+                    base_var.clone()
+                } else {
+                    var
+                },
+                color: color.or_else(|| base_color.clone()),
+                value_color: if value_color.is_empty() {
+                    base_value_color.clone()
+                } else {
+                    value_color
+                },
+                icon: icon.or_else(|| base_icon.clone()),
+                segment: segment.merged_with(base_segment),
+            },
+            (command, _) => command,
+        }
+    }
+
+    /// 全モジュール共通の見た目の設定
+    pub fn segment_options(&self) -> &SegmentOptions {
+        match self {
+            Self::Os { segment, .. }
+            | Self::Pwd { segment, .. }
+            | Self::Time { segment, .. }
+            | Self::Git { segment, .. }
+            | Self::Cmd { segment, .. }
+            | Self::Custom { segment, .. }
+            | Self::Env { segment, .. } => segment,
+        }
+    }
+
+    pub fn segment_options_mut(&mut self) -> &mut SegmentOptions {
+        match self {
+            Self::Os { segment, .. }
+            | Self::Pwd { segment, .. }
+            | Self::Time { segment, .. }
+            | Self::Git { segment, .. }
+            | Self::Cmd { segment, .. }
+            | Self::Custom { segment, .. }
+            | Self::Env { segment, .. } => segment,
+        }
+    }
+
+    /// 色の指定に含まれる `@name` を `palette` の色に置き換える。
+    /// 設定ファイルとテーマを適用した後に1度だけ呼ぶ。パレットに無い名前は項目のキーと一緒に返す。
+    pub fn resolve_palette(&mut self, palette: &Palette) -> Vec<(&'static str, ColorParseError)> {
+        fn resolve(
+            key: &'static str,
+            color: &mut Option<Color>,
+            palette: &Palette,
+            unresolved: &mut Vec<(&'static str, ColorParseError)>,
+        ) {
+            if let Some(color) = color
+                && let Err(e) = color.resolve(palette)
+            {
+                unresolved.push((key, e));
+            }
+        }
+        // 文字列のまま持っている色は、解決した色の表記に書き換える
+        fn resolve_str(
+            key: &'static str,
+            color: &mut String,
+            palette: &Palette,
+            unresolved: &mut Vec<(&'static str, ColorParseError)>,
+        ) {
+            if let Ok(mut parsed @ Color::Palette(_)) = color.parse::<Color>() {
+                match parsed.resolve(palette) {
+                    Ok(()) => *color = parsed.to_string(),
+                    Err(e) => unresolved.push((key, e)),
+                }
+            }
+        }
+
+        let mut unresolved = Vec::new();
+        resolve(
+            "bg",
+            &mut self.segment_options_mut().bg,
+            palette,
+            &mut unresolved,
+        );
         match self {
-            Self::Os { color } => {
+            Self::Os { color, .. } | Self::Pwd { color, .. } | Self::Time { color, .. } => {
+                if let Some(color) = color {
+                    resolve_str("color", color, palette, &mut unresolved);
+                }
+            }
+            Self::Git { options, .. } => {
+                for (key, color) in options.colors_mut() {
+                    resolve(key, color, palette, &mut unresolved);
+                }
+            }
+            Self::Cmd {
+                color,
+                success_color,
+                failure_color,
+                ..
+            } => {
+                if let Some(color) = color {
+                    resolve_str("color", color, palette, &mut unresolved);
+                }
+                resolve("success_color", success_color, palette, &mut unresolved);
+                resolve("failure_color", failure_color, palette, &mut unresolved);
+            }
+            Self::Custom { color, .. } => resolve("color", color, palette, &mut unresolved),
+            Self::Env {
+                color, value_color, ..
+            } => {
+                resolve("color", color, palette, &mut unresolved);
+                for value_color in value_color {
+                    let color = &mut value_color.color;
+                    if let Err(e) = color.resolve(palette) {
+                        unresolved.push(("value_color", e));
+                    }
+                }
+            }
+        }
+        unresolved
+    }
+
+    /// 複数のモジュールを1プロセス内で並行に実行し、指定された順にセグメントを連結して返す。
+    /// 制限時間は全モジュール共通の開始時刻から数える。
+    pub fn exec_all(
+        commands: &[Commands],
+        budget: &TimeBudget,
+        context: &Context,
+    ) -> Vec<PromptSegment> {
+        Self::exec_grouped(commands, budget, context).concat()
+    }
+
+    /// `exec_all` と同じだが、モジュールごとのセグメントを分けたまま返す。
+    /// 複数行のレイアウトで、まとめて評価した結果を行や左右に振り分けるときに使う。
+    pub fn exec_grouped(
+        commands: &[Commands],
+        budget: &TimeBudget,
+        context: &Context,
+    ) -> Vec<Vec<PromptSegment>> {
+        let started = Instant::now();
+        let context = Arc::new(context.clone());
+        let pending: Vec<_> = commands
+            .iter()
+            .map(|command| (command, command.spawn(&context)))
+            .collect();
+        pending
+            .into_iter()
+            .map(|(command, rx)| {
+                let deadline = budget
+                    .for_module(command.name())
+                    .map(|budget| started + budget);
+                command.wait(rx, deadline)
+            })
+            .collect()
+    }
+
+    /// 制限時間内にモジュールを実行する。
+    /// 時間内に終わらなかった場合やパニックした場合はプレースホルダーを返し、
+    /// 評価中のスレッドはそのまま放置する。
+    pub fn exec_within(&self, budget: Option<Duration>, context: &Context) -> Vec<PromptSegment> {
+        let Some(budget) = budget else {
+            return self.exec(context);
+        };
+        self.wait(
+            self.spawn(&Arc::new(context.clone())),
+            Some(Instant::now() + budget),
+        )
+    }
+
+    // 別スレッドでモジュールを評価し、結果を受け取るチャネルを返す。
+    // 放置されたスレッドが `MAX_MODULE_THREADS` に達している間は評価せず、
+    // 送信側を閉じたチャネルを返すのですぐにプレースホルダーになる。
+    fn spawn(&self, context: &Arc<Context>) -> mpsc::Receiver<Vec<PromptSegment>> {
+        let (tx, rx) = mpsc::channel();
+        let Some(slot) = ModuleThread::acquire() else {
+            return rx;
+        };
+        let command = self.clone();
+        let context = Arc::clone(context);
+        thread::spawn(move || {
+            let _slot = slot;
+            let _ = tx.send(command.exec(&context));
+        });
+        rx
+    }
+
+    fn wait(
+        &self,
+        rx: mpsc::Receiver<Vec<PromptSegment>>,
+        deadline: Option<Instant>,
+    ) -> Vec<PromptSegment> {
+        let result = match deadline {
+            Some(deadline) => rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok(),
+            None => rx.recv().ok(),
+        };
+        result.unwrap_or_else(|| vec![self.placeholder()])
+    }
+
+    /// 設定ファイルなどでモジュールを指す名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Os { .. } => "os",
+            Self::Pwd { .. } => "pwd",
+            Self::Time { .. } => "time",
+            Self::Git { .. } => "git",
+            Self::Cmd { .. } => "cmd",
+            Self::Custom { .. } => "custom",
+            Self::Env { .. } => "env",
+        }
+    }
+
+    /// `--format` の書式で使えるフィールド
+    pub fn format_fields(&self) -> &'static [&'static str] {
+        match self {
+            Self::Os { .. } => os::FIELDS,
+            Self::Pwd { .. } => pwd::FIELDS,
+            Self::Time { .. } => time::FIELDS,
+            Self::Git { .. } => git::FIELDS,
+            Self::Cmd { .. } => cmd::FIELDS,
+            Self::Custom { .. } => custom::FIELDS,
+            Self::Env { .. } => env::FIELDS,
+        }
+    }
+
+    /// 評価が間に合わなかったときに表示する、モジュール自身の色を薄くしたセグメント
+    pub fn placeholder(&self) -> PromptSegment {
+        let icons = self.segment_options().icons.unwrap_or_default();
+        let icon = match self {
+            Self::Os { .. } => os::OTHER,
+            Self::Pwd { .. } => pwd::FOLDER_ICON,
+            Self::Time { .. } => time::CLOCK_ICON,
+            Self::Git { .. } => git::GIT_ICON,
+            Self::Cmd { .. } => cmd::COMMAND_ICON,
+            Self::Custom { .. } => custom::CUSTOM_ICON,
+            Self::Env { .. } => env::VARIABLE_ICON,
+        };
+        // ASCIIでは記号を持たないアイコンもある
+        let content = match icon.get(icons) {
+            "" => "…".to_string(),
+            icon => format!("{} …", icon),
+        };
+        let mut segment = PromptSegment {
+            content,
+            color: self.color(),
+            style: Style::DIM,
+            module: Some(self.name().to_string()),
+            ..Default::default()
+        };
+        self.segment_options().apply(&mut segment);
+        segment
+    }
+
+    // モジュールが主に使う色 (`--color` の指定、無ければ既定の色)
+    fn color(&self) -> Option<Color> {
+        let parse = |color: &Option<String>| color.as_ref().and_then(|c| c.parse::<Color>().ok());
+        match self {
+            Self::Os { color, .. } => Some(parse(color).unwrap_or(Color::White)),
+            Self::Pwd { color, .. } => Some(parse(color).unwrap_or(Color::Cyan)),
+            Self::Time { color, .. } => Some(parse(color).unwrap_or(Color::Magenta)),
+            Self::Git { options, .. } => {
+                Some(options.default_color_option.clone().unwrap_or(Color::Blue))
+            }
+            // 終了ステータスが分かるまで成功か失敗かの色は決まらない
+            Self::Cmd { color, .. } => parse(color),
+            Self::Custom { color, .. } => color.clone(),
+            Self::Env { color, .. } => Some(color.clone().unwrap_or(Color::Yellow)),
+        }
+    }
+
+    /// このスレッドでモジュールを実行し、全体と処理段階ごとの時間を計る (`timings` サブコマンド用)
+    pub fn exec_timed(&self, context: &Context) -> (Vec<PromptSegment>, Vec<Timing>) {
+        let (segments, total, phases) = profile(|| self.exec(context));
+        let timing = |phase: &str, elapsed: Duration| Timing {
+            module: self.name().to_string(),
+            phase: phase.to_string(),
+            ms: elapsed.as_secs_f64() * 1000.0,
+        };
+        let mut timings: Vec<Timing> = phases
+            .into_iter()
+            .map(|(phase, elapsed)| timing(phase, elapsed))
+            .collect();
+        timings.push(timing("total", total));
+        (segments, timings)
+    }
+
+    /// セグメントの元になった情報 (`explain` サブコマンド用)
+    pub fn explain(&self, context: &Context) -> Vec<(&'static str, String)> {
+        match self {
+            Self::Os { .. } => os::explain_os_icon(),
+            Self::Pwd { .. } => pwd::explain_smart_pwd(context),
+            Self::Time { .. } => time::explain_time(),
+            Self::Git { path, .. } => git::explain_git_status(path, context),
+            Self::Cmd {
+                last_status,
+                last_command_executed,
+                ..
+            } => cmd::explain_execution_info(last_status, last_command_executed, context),
+            Self::Custom { command, file, .. } => {
+                custom::explain_custom_output(command, file, context)
+            }
+            Self::Env {
+                var, value_color, ..
+            } => env::explain_env_vars(var, value_color, context),
+        }
+    }
+
+    /// モジュールを評価する。カレントディレクトリと環境変数は `context` のものを使う。
+    pub fn exec(&self, context: &Context) -> Vec<PromptSegment> {
+        let format = self.segment_options().format.as_deref();
+        let icons = self.segment_options().icons.unwrap_or_default();
+        let mut segments = match self {
+            Self::Os { color, .. } => {
                 let parsed_color = color.as_ref().and_then(|c| c.parse::<Color>().ok());
-                vec![os::get_os_icon(parsed_color)]
+                os::get_os_icon(parsed_color, icons, format)
             }
-            Self::Pwd { color } => {
+            Self::Pwd { color, .. } => {
                 let parsed_color = color.as_ref().and_then(|c| c.parse::<Color>().ok());
-                pwd::get_smart_pwd(parsed_color)
+                pwd::get_smart_pwd(context, parsed_color, icons, format)
             }
-            Self::Time { color } => {
+            Self::Time { color, .. } => {
                 let parsed_color = color.as_ref().and_then(|c| c.parse::<Color>().ok());
-                vec![time::get_time(parsed_color)]
-            }
-            Self::Git { options, path } => {
-                let parsed_default_color = options.default_color_option;
-                let parsed_git_icon_color = options.git_icon_color_option;
-                let parsed_branch_color = options.branch_color_option;
-                let parsed_staged_color = options.staged_color_option;
-                let parsed_unstaged_color = options.unstaged_color_option;
-                let parsed_untracked_color = options.untracked_color_option;
-                let parsed_conflict_color = options.conflict_color_option;
-                let parsed_stashed_color = options.stashed_color_option;
-                let parsed_clean_color = options.clean_color_option;
-                let parsed_ahead_color = options.ahead_color_option;
-                let parsed_behind_color = options.behind_color_option;
-                git::get_git_status(
-                    GitStatusOptions {
-                        default_color_option: parsed_default_color,
-                        git_icon_color_option: parsed_git_icon_color,
-                        branch_color_option: parsed_branch_color,
-                        staged_color_option: parsed_staged_color,
-                        unstaged_color_option: parsed_unstaged_color,
-                        untracked_color_option: parsed_untracked_color,
-                        conflict_color_option: parsed_conflict_color,
-                        stashed_color_option: parsed_stashed_color,
-                        clean_color_option: parsed_clean_color,
-                        ahead_color_option: parsed_ahead_color,
-                        behind_color_option: parsed_behind_color,
-                    },
-                    path,
-                )
+                time::get_time(parsed_color, icons, format)
+            }
+            Self::Git { options, path, .. } => {
+                git::get_git_status(options, path, context, icons, format)
             }
             Self::Cmd {
                 last_status,
                 last_command_executed,
                 color,
+                success_color,
+                failure_color,
+                ..
             } => {
                 let parsed_color = color.as_ref().and_then(|c| c.parse::<Color>().ok());
-                vec![cmd::get_execution_info(
+                cmd::get_execution_info(
                     last_status,
                     last_command_executed,
+                    context,
                     parsed_color,
-                )]
+                    success_color.clone(),
+                    failure_color.clone(),
+                    icons,
+                    format,
+                )
+            }
+            Self::Custom {
+                command,
+                file,
+                timeout,
+                cache_ttl,
+                color,
+                ..
+            } => custom::get_custom_output(
+                command,
+                file,
+                context,
+                *timeout,
+                *cache_ttl,
+                color.clone(),
+                format,
+            ),
+            Self::Env {
+                var,
+                color,
+                value_color,
+                icon,
+                ..
+            } => env::get_env_vars(
+                var,
+                context,
+                color.clone(),
+                value_color,
+                icon,
+                icons,
+                format,
+            ),
+        };
+        // どのモジュールが出力したセグメントかを記録し、共通の見た目を適用する
+        for segment in &mut segments {
+            segment.module = Some(self.name().to_string());
+            self.segment_options().apply(segment);
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list_keeps_module_order_and_options() {
+        let commands = Commands::parse_list([
+            "os",
+            "::",
+            "git",
+            "--branch-color-option",
+            "red",
+            "::",
+            "time",
+            "--color",
+            "blue",
+        ])
+        .unwrap();
+
+        assert_eq!(commands.len(), 3);
+        assert!(matches!(commands[0], Commands::Os { color: None, .. }));
+        assert!(matches!(
+            &commands[1],
+            Commands::Git { options, .. } if options.branch_color_option == Some(Color::Red)
+        ));
+        assert!(matches!(&commands[2], Commands::Time { color: Some(c), .. } if c == "blue"));
+    }
+
+    #[test]
+    fn test_resolve_palette_replaces_names_once() {
+        let mut commands = Commands::parse_list([
+            "time",
+            "--color",
+            "@accent",
+            "::",
+            "git",
+            "--branch-color-option",
+            "@accent",
+            "--bg",
+            "@missing",
+            "::",
+            "env",
+            "--value-color",
+            "prod=@accent",
+        ])
+        .unwrap();
+        let palette = Palette::from([("accent".to_string(), Color::Indexed(208))]);
+
+        assert!(commands[0].resolve_palette(&palette).is_empty());
+        assert!(matches!(&commands[0], Commands::Time { color: Some(c), .. } if c == "208"));
+        let unresolved = commands[1].resolve_palette(&palette);
+        assert_eq!(
+            unresolved,
+            [("bg", ColorParseError::UnknownPaletteColor("missing".into()))]
+        );
+        let Commands::Git {
+            options, segment, ..
+        } = &commands[1]
+        else {
+            panic!("expected git");
+        };
+        assert_eq!(options.branch_color_option, Some(Color::Indexed(208)));
+        // パレットに無い名前は端末の既定の色で表示される
+        assert_eq!(
+            segment
+                .bg
+                .as_ref()
+                .unwrap()
+                .as_ansi_bg_code(ColorDepth::TrueColor),
+            "49"
+        );
+        assert!(commands[2].resolve_palette(&palette).is_empty());
+        assert!(
+            matches!(&commands[2], Commands::Env { value_color, .. } if value_color[0].to_string() == "prod=208")
+        );
+    }
+
+    #[test]
+    fn test_time_budget_resolution() {
+        let budget: TimeBudget = toml::from_str("default_ms = 300\ngit = 0\npwd = 50").unwrap();
+        assert_eq!(budget.for_module("git"), None);
+        assert_eq!(budget.for_module("pwd"), Some(Duration::from_millis(50)));
+        assert_eq!(budget.for_module("os"), Some(Duration::from_millis(300)));
+        assert_eq!(
+            TimeBudget::default().for_module("os"),
+            Some(Duration::from_millis(DEFAULT_TIMEOUT_MS))
+        );
+    }
+
+    #[test]
+    fn test_exec_within_returns_result_in_time() {
+        let command = Commands::Time {
+            color: None,
+            segment: SegmentOptions::default(),
+        };
+        let segments = command.exec_within(Some(Duration::from_secs(5)), &Context::current());
+        assert_ne!(segments[0].style, Style::DIM);
+    }
+
+    #[test]
+    fn test_module_over_budget_is_replaced_by_placeholder() {
+        let command = Commands::Custom {
+            command: Some("sleep 1; echo done".to_string()),
+            file: None,
+            timeout: Some(0),
+            cache_ttl: None,
+            color: Some(Color::Green),
+            segment: SegmentOptions {
+                icons: Some(IconSet::Ascii),
+                ..Default::default()
+            },
+        };
+        let started = Instant::now();
+        let segments = command.exec_within(Some(Duration::from_millis(50)), &Context::current());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].content, "* …");
+        assert_eq!(segments[0].color, Some(Color::Green));
+        assert_eq!(segments[0].style, Style::DIM);
+        assert_eq!(segments[0].module.as_deref(), Some("custom"));
+    }
+
+    #[test]
+    fn test_placeholder_uses_module_icon_from_icon_set() {
+        let placeholder = |args: &[&str], icons| {
+            let mut command = Commands::parse_list(args.iter().copied())
+                .unwrap()
+                .remove(0);
+            command.segment_options_mut().icons = Some(icons);
+            command.placeholder().content
+        };
+        assert_eq!(placeholder(&["time"], IconSet::Unicode), "◷ …");
+        assert_eq!(placeholder(&["time"], IconSet::Ascii), "…");
+        assert_eq!(
+            placeholder(&["env", "--var", "AWS_PROFILE"], IconSet::Unicode),
+            "$ …"
+        );
+        assert_eq!(
+            placeholder(&["cmd", "--last-status", "STATUS"], IconSet::Ascii),
+            "> …"
+        );
+    }
+
+    #[test]
+    fn test_exec_all_preserves_module_order() {
+        let dir = tempfile::tempdir().unwrap();
+        git2::Repository::init(dir.path()).unwrap();
+        let commands = vec![
+            Commands::Time {
+                color: Some("blue".to_string()),
+                segment: SegmentOptions::default(),
+            },
+            Commands::Git {
+                options: Default::default(),
+                path: Some(dir.path().to_path_buf()),
+                segment: SegmentOptions::default(),
+            },
+            Commands::Os {
+                color: Some("red".to_string()),
+                segment: SegmentOptions::default(),
+            },
+        ];
+
+        // テストでは実際のキャッシュディレクトリに書き込まない
+        let context = Context {
+            cache_dir: None,
+            ..Context::current()
+        };
+        let segments = Commands::exec_all(&commands, &TimeBudget::default(), &context);
+        let mut order: Vec<&str> = segments
+            .iter()
+            .filter_map(|s| s.module.as_deref())
+            .collect();
+        order.dedup();
+        assert_eq!(order, ["time", "git", "os"]);
+        assert_eq!(segments.first().unwrap().color, Some(Color::Blue));
+        assert!(
+            segments
+                .iter()
+                .any(|s| s.module.as_deref() == Some("git") && s.content == "empty")
+        );
+        assert_eq!(segments.last().unwrap().color, Some(Color::Red));
+    }
+
+    #[test]
+    fn test_exec_all_runs_modules_concurrently() {
+        let sleep = |output: &str| Commands::Custom {
+            command: Some(format!("sleep 0.4; echo {}", output)),
+            file: None,
+            timeout: Some(0),
+            cache_ttl: None,
+            color: None,
+            segment: SegmentOptions::default(),
+        };
+        let commands = vec![sleep("first"), sleep("second")];
+        let unlimited = TimeBudget {
+            default_ms: Some(0),
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let segments = Commands::exec_all(&commands, &unlimited, &Context::current());
+        // 順に実行すれば 0.8 秒以上かかる
+        assert!(started.elapsed() < Duration::from_millis(800));
+        let contents: Vec<&str> = segments.iter().map(|s| s.content.as_str()).collect();
+        assert_eq!(contents, ["first", "second"]);
+    }
+
+    // 並行実行による短縮を確認するための計測。`cargo test -- --ignored --nocapture` で実行する。
+    #[test]
+    #[ignore]
+    fn bench_exec_all_parallel_vs_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        git2::Repository::init(dir.path()).unwrap();
+        for i in 0..200 {
+            let sub = dir.path().join(format!("dir{}", i));
+            std::fs::create_dir(&sub).unwrap();
+            for j in 0..20 {
+                std::fs::write(sub.join(format!("file{}.txt", j)), "x").unwrap();
             }
         }
+        let git = Commands::Git {
+            options: Default::default(),
+            path: Some(dir.path().to_path_buf()),
+            segment: SegmentOptions::default(),
+        };
+        let commands = vec![
+            Commands::Os {
+                color: None,
+                segment: SegmentOptions::default(),
+            },
+            Commands::Pwd {
+                color: None,
+                segment: SegmentOptions::default(),
+            },
+            git.clone(),
+            git,
+            Commands::Time {
+                color: None,
+                segment: SegmentOptions::default(),
+            },
+        ];
+        let unlimited = TimeBudget {
+            default_ms: Some(0),
+            ..Default::default()
+        };
+        let context = Context {
+            cache_dir: None,
+            ..Context::current()
+        };
+
+        let started = Instant::now();
+        let sequential: Vec<PromptSegment> =
+            commands.iter().flat_map(|c| c.exec(&context)).collect();
+        let sequential_time = started.elapsed();
+
+        let started = Instant::now();
+        let parallel = Commands::exec_all(&commands, &unlimited, &context);
+        let parallel_time = started.elapsed();
+
+        println!(
+            "sequential: {:?}, parallel: {:?}",
+            sequential_time, parallel_time
+        );
+        assert_eq!(sequential.len(), parallel.len());
+    }
+
+    #[test]
+    fn test_parse_list_rejects_unknown_module() {
+        assert!(Commands::parse_list(["os", "::", "nope"]).is_err());
     }
 }
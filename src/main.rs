@@ -1,25 +1,466 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::fs;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use zsh_prompts::*;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: CliCommand,
+    /// Output format of the rendered prompt [default: raw]
+    #[arg(long, value_enum, global = true)]
+    output: Option<OutputMode>,
+    /// Default time budget per module in milliseconds (0 = unlimited) [default: 1000]
+    #[arg(long, global = true, value_name = "MS")]
+    timeout_ms: Option<u64>,
+    /// Number of colors the terminal supports [default: auto]
+    #[arg(long, value_enum, global = true)]
+    color_depth: Option<ColorDepth>,
+    /// Print plain text without colors or attributes (same as `--color-depth none`)
+    #[arg(long, global = true, conflicts_with = "color_depth")]
+    no_color: bool,
+    /// Color theme: a built-in name (default, solarized, high-contrast, monochrome),
+    /// a file in the `themes` directory next to the config file, or a path
+    #[arg(long, global = true, value_name = "NAME")]
+    theme: Option<String>,
+    /// Glyphs used for module icons [default: $ZSH_PROMPTS_ICONS, then nerd]
+    #[arg(long, value_enum, global = true, value_name = "SET")]
+    icons: Option<IconSet>,
+    /// Fail with a list of problems instead of rendering when the flags or config are invalid
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Always render in this process instead of asking a running daemon
+    #[arg(long, global = true)]
+    no_daemon: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    #[command(flatten)]
+    Module(Commands),
+    /// Render several modules at once
+    ///
+    /// Modules are separated by `::` and rendered in the given order,
+    /// e.g. `prompt os :: pwd :: git --branch-color-option red :: time`.
+    /// Without arguments the modules listed in the config file are rendered.
+    Prompt {
+        /// Render the right prompt (`right_modules` in the config file)
+        #[arg(long)]
+        right: bool,
+        /// Render the configured left and right prompts separated by a NUL byte,
+        /// fitting both sides into one `--max-width` budget
+        #[arg(long, conflicts_with_all = ["right", "modules"])]
+        both: bool,
+        /// Terminal width used to fill multi-line layouts [default: $COLUMNS]
+        #[arg(long)]
+        columns: Option<usize>,
+        /// Drop low-priority segments until the prompt fits this fraction of the terminal width
+        #[arg(long, value_name = "RATIO")]
+        max_width: Option<f64>,
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = false,
+            value_name = "MODULE"
+        )]
+        modules: Vec<String>,
+    },
+    /// Validate the config file, theme, environment and the given modules
+    ///
+    /// Modules are given like `prompt`, e.g. `check pwd --color grean :: git`.
+    /// Exits with status 1 if any problem is found.
+    Check {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = false,
+            value_name = "MODULE"
+        )]
+        modules: Vec<String>,
+    },
+    /// Show why each segment is displayed
+    ///
+    /// Runs the modules like `prompt` and prints every segment together with
+    /// the data it was built from, such as the files counted by `git` or the
+    /// exit status read by `cmd`. Without arguments the configured modules are explained.
+    Explain {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = false,
+            value_name = "MODULE"
+        )]
+        modules: Vec<String>,
+    },
+    /// Measure how long each module takes
+    ///
+    /// Runs the modules one after another in this process and prints the time
+    /// spent in each module and in the phases of `git` (discover, status,
+    /// ahead-behind, stash). The status phase notes whether the cache was hit. Use `--output json` for machine-readable output.
+    Timings {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = false,
+            value_name = "MODULE"
+        )]
+        modules: Vec<String>,
+    },
+    /// Print the shell integration script
+    ///
+    /// Add `eval "$(zsh-prompts init zsh)"` to your ~/.zshrc.
+    Init {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Run a background daemon that renders prompts for clients
+    ///
+    /// While the daemon is running, other invocations send their modules
+    /// over a per-user Unix socket instead of evaluating them in-process.
+    #[cfg(unix)]
+    Daemon {
+        /// Socket path [default: $XDG_RUNTIME_DIR/zsh-prompts.sock]
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
 }
 
 fn main() -> io::Result<()> {
     let cli = Cli::parse();
+    match cli.command {
+        CliCommand::Init { shell } => {
+            return io::stdout().write_all(init_script(shell).as_bytes());
+        }
+        #[cfg(unix)]
+        CliCommand::Daemon { socket } => {
+            return daemon::run(&socket.unwrap_or_else(daemon::socket_path));
+        }
+        _ => {}
+    }
+
+    let mut problems: Vec<Diagnostic> = Vec::new();
+    // 値の誤った項目はその項目だけを除いて読み込む
+    let (config, entry_errors) = Config::load().unwrap_or_else(|e| {
+        problems.push(e.into());
+        (Config::default(), Vec::new())
+    });
+
+    // `check` と strict モードでは設定全体を検査し、読み込み時に除いた項目も報告する。
+    // そうでなければ、既定の書式に置き換わる書式の誤りだけをここで1度検査する。
+    let strict = cli.strict || config.strict;
+    let checking = strict || matches!(cli.command, CliCommand::Check { .. });
+    let config_path = Config::path();
+    let path = config_path
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "config".to_string());
+    if checking {
+        problems.extend(entry_errors.into_iter().map(Diagnostic::from));
+        let text = config_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok());
+        problems.extend(check_config(&config, text.as_deref(), &path));
+        if let Some(theme) = &cli.theme {
+            problems.extend(check_theme(theme, "--theme"));
+        }
+        problems.extend(check_environment());
+    } else {
+        problems.extend(check_config_formats(&config, &path));
+    }
+    // コマンドラインのモジュールはプロンプトを描くたびに渡されるので、
+    // 毎回報告しないように `check` と strict モードでだけ検査する
+    let cli_problems = |command: &Commands, location: &str| {
+        if checking {
+            check_command(command, location, Source::CommandLine, &config.palette)
+        } else {
+            Vec::new()
+        }
+    };
+    if let CliCommand::Check { modules } = &cli.command {
+        match Commands::parse_list(modules) {
+            Ok(commands) => {
+                for command in &commands {
+                    problems.extend(cli_problems(command, "check"));
+                }
+            }
+            Err(e) => e.exit(),
+        }
+        report_check(&config, &cli.theme, &problems);
+        std::process::exit(i32::from(!problems.is_empty()));
+    }
 
-    let segments: Vec<PromptSegment> = cli.command.exec();
+    // `prompt` の場合は端末の桁数と、プロンプトが使ってよい桁数
+    let (columns, max_width) = match &cli.command {
+        CliCommand::Prompt {
+            columns, max_width, ..
+        } => {
+            let columns = columns.unwrap_or_else(terminal_columns);
+            let ratio = max_width.or(config.max_width);
+            (columns, ratio.map(|ratio| width_budget(columns, ratio)))
+        }
+        _ => (terminal_columns(), None),
+    };
+    // 複数行のレイアウトを使う場合は右側かどうか
+    let mut layout: Option<bool> = None;
+    // 左右をまとめて描く場合は、左側のモジュールの数
+    let mut both: Option<usize> = None;
+    let explain = matches!(cli.command, CliCommand::Explain { .. });
+    let timings = matches!(cli.command, CliCommand::Timings { .. });
+    let commands: Vec<Commands> = match cli.command {
+        CliCommand::Module(command) => {
+            problems.extend(cli_problems(&command, "zsh-prompts"));
+            vec![config.apply_to(command)]
+        }
+        CliCommand::Prompt { both: true, .. } if !config.lines.is_empty() => {
+            layout = Some(false);
+            let left = layout_commands(&config.lines, false);
+            both = Some(left.len());
+            [left, layout_commands(&config.lines, true)].concat()
+        }
+        CliCommand::Prompt { both: true, .. } => {
+            both = Some(config.modules.len());
+            [config.modules.clone(), config.right_modules.clone()].concat()
+        }
+        CliCommand::Prompt { right, modules, .. }
+            if modules.is_empty() && !config.lines.is_empty() =>
+        {
+            layout = Some(right);
+            layout_commands(&config.lines, right)
+        }
+        CliCommand::Prompt { right, modules, .. } if modules.is_empty() => {
+            if right {
+                config.right_modules.clone()
+            } else {
+                config.modules.clone()
+            }
+        }
+        CliCommand::Explain { modules } | CliCommand::Timings { modules } if modules.is_empty() => {
+            if config.lines.is_empty() {
+                [config.modules.clone(), config.right_modules.clone()].concat()
+            } else {
+                [
+                    layout_commands(&config.lines, false),
+                    layout_commands(&config.lines, true),
+                ]
+                .concat()
+            }
+        }
+        CliCommand::Explain { modules } | CliCommand::Timings { modules } => {
+            match Commands::parse_list(modules) {
+                Ok(commands) => commands
+                    .into_iter()
+                    .map(|command| {
+                        let location = if explain { "explain" } else { "timings" };
+                        problems.extend(cli_problems(&command, location));
+                        config.apply_to(command)
+                    })
+                    .collect(),
+                Err(e) => e.exit(),
+            }
+        }
+        CliCommand::Prompt { modules, .. } => match Commands::parse_list(modules) {
+            Ok(commands) => commands
+                .into_iter()
+                .map(|command| {
+                    problems.extend(cli_problems(&command, "prompt"));
+                    config.apply_to(command)
+                })
+                .collect(),
+            Err(e) => e.exit(),
+        },
+        CliCommand::Init { .. } | CliCommand::Check { .. } => unreachable!(),
+        #[cfg(unix)]
+        CliCommand::Daemon { .. } => unreachable!(),
+    };
 
-    let full_output: String = segments
+    // strict モードでは問題があれば描画せずに終了する。
+    // そうでなければ読み込めなかった設定ファイルと書式の誤りだけを報告し、既定値で描画を続ける。
+    for problem in &problems {
+        eprintln!("Error: {}", problem);
+    }
+    if strict && !problems.is_empty() {
+        std::process::exit(1);
+    }
+
+    // モジュールごとに指定していない色はテーマで補う
+    let theme = match cli.theme.as_deref().or(config.theme.as_deref()) {
+        Some(name) => Theme::load(name).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            Theme::default()
+        }),
+        None => Theme::default(),
+    };
+    let mut commands: Vec<Commands> = commands
         .into_iter()
-        .map(|segment| segment.format())
-        .collect::<Vec<String>>()
-        .join(" ");
+        .map(|command| theme.apply_to(command))
+        .collect();
+
+    // モジュールごとに指定が無ければ、CLI、環境変数、設定ファイルの順にアイコンの種類を決める
+    let icons = cli
+        .icons
+        .or_else(IconSet::from_env)
+        .or(config.icons)
+        .unwrap_or_default();
+    // `@name` の色はここで1度だけパレットから引く。パレットに無い名前は `check` で報告される。
+    for command in &mut commands {
+        command.segment_options_mut().icons.get_or_insert(icons);
+        command.resolve_palette(&config.palette);
+    }
+
+    // `explain` と `timings` は制限時間を設けずにこのプロセスで評価する
+    if explain {
+        report_explain(&commands);
+        return Ok(());
+    }
+    if timings {
+        report_timings(&commands, cli.output.or(config.output).unwrap_or_default());
+        return Ok(());
+    }
+
+    let mut budget = config.timeout.clone();
+    if let Some(ms) = cli.timeout_ms {
+        budget.default_ms = Some(ms);
+    }
+
+    // デーモンが起動していればそちらで評価し、そうでなければこのプロセスで評価する
+    let context = Context::current();
+    #[cfg(unix)]
+    let from_daemon = if cli.no_daemon {
+        None
+    } else {
+        daemon::request(&commands, &budget, &context)
+    };
+    #[cfg(not(unix))]
+    let from_daemon = None;
+    let groups: Vec<Vec<PromptSegment>> =
+        from_daemon.unwrap_or_else(|| Commands::exec_grouped(&commands, &budget, &context));
+
+    let output = cli.output.or(config.output).unwrap_or_default();
+    // NO_COLOR 環境変数は `auto` の判定の中で考慮される
+    let depth = if cli.no_color {
+        ColorDepth::NoColor
+    } else {
+        cli.color_depth.or(config.color_depth).unwrap_or_default()
+    };
+    // 左右の幅は合わせて `max_width` に収める
+    let full_output = match (both, layout) {
+        (Some(_), Some(_)) if output != OutputMode::Json => {
+            let (left, right) =
+                render_layout_both(&config.lines, &groups, columns, max_width, output, depth);
+            format!("{}\0{}", left, right)
+        }
+        (Some(left_count), _) => {
+            let (left, right) = groups.split_at(left_count.min(groups.len()));
+            let (mut left, mut right) = (left.concat(), right.concat());
+            if let Some(max_width) = max_width
+                && output != OutputMode::Json
+            {
+                fit_to_width(&mut left, &mut right, max_width, depth.resolve());
+            }
+            format!(
+                "{}\0{}",
+                render(&left, output, depth),
+                render(&right, output, depth)
+            )
+        }
+        (None, Some(right)) => render_layout(
+            &config.lines,
+            &groups,
+            right,
+            columns,
+            max_width,
+            output,
+            depth,
+        ),
+        (None, None) => {
+            let mut segments = groups.concat();
+            if let Some(max_width) = max_width
+                && output != OutputMode::Json
+            {
+                fit_to_width(&mut segments, &mut Vec::new(), max_width, depth.resolve());
+            }
+            render(&segments, output, depth)
+        }
+    };
 
     io::stdout().write_all(full_output.as_bytes())?;
     Ok(())
 }
+
+// `check` サブコマンドの結果を表示する
+fn report_check(config: &Config, theme: &Option<String>, problems: &[Diagnostic]) {
+    match Config::path() {
+        Some(path) if path.is_file() => println!("Config file: {}", path.display()),
+        Some(path) => println!(
+            "Config file: {} (not found, using defaults)",
+            path.display()
+        ),
+        None => println!("Config file: none (using defaults)"),
+    }
+    let theme = theme.as_deref().or(config.theme.as_deref());
+    println!("Theme: {}", theme.unwrap_or("default"));
+    for problem in problems {
+        println!("\n{}", problem);
+    }
+    match problems.len() {
+        0 => println!("No problems found"),
+        1 => println!("\n1 problem found"),
+        n => println!("\n{} problems found", n),
+    }
+}
+
+// `explain` サブコマンドの結果を表示する
+fn report_explain(commands: &[Commands]) {
+    let context = Context::current();
+    for (i, command) in commands.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{}", command.name());
+        // モジュールを実行するとキャッシュが更新されるので、その前の状態を説明する
+        let explanation = command.explain(&context);
+        for segment in command.exec(&context) {
+            let mut line = format!("  segment {:?}", segment.content);
+            if let Some(color) = segment.color {
+                line.push_str(&format!(" color={}", color));
+            }
+            if let Some(background) = segment.background {
+                line.push_str(&format!(" bg={}", background));
+            }
+            if segment.style != Style::NONE {
+                line.push_str(&format!(" style={}", segment.style));
+            }
+            if segment.priority != DEFAULT_PRIORITY {
+                line.push_str(&format!(" priority={}", segment.priority));
+            }
+            println!("{}", line);
+        }
+        for (key, value) in explanation {
+            println!("  {}: {}", key, value);
+        }
+    }
+}
+
+// `timings` サブコマンドの結果を表示する。
+// 計測が互いに影響しないよう、モジュールは1つずつ順に実行する。
+fn report_timings(commands: &[Commands], output: OutputMode) {
+    let context = Context::current();
+    let mut timings: Vec<Timing> = Vec::new();
+    let mut total = 0.0;
+    for command in commands {
+        let (_, module_timings) = command.exec_timed(&context);
+        total += module_timings.last().map_or(0.0, |timing| timing.ms);
+        timings.extend(module_timings);
+    }
+    timings.push(Timing {
+        module: "all".to_string(),
+        phase: "total".to_string(),
+        ms: total,
+    });
+    match output {
+        OutputMode::Json => println!("{}", serde_json::to_string(&timings).unwrap_or_default()),
+        OutputMode::Raw | OutputMode::Zsh => print!("{}", timings_table(&timings)),
+    }
+}
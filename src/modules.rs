@@ -1,144 +1,596 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::fmt;
-use std::str::FromStr;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
 
 pub mod cmd;
+mod color;
+mod context;
+pub mod custom;
+pub mod env;
 pub mod git;
+mod icons;
 pub mod os;
 pub mod pwd;
 pub mod time;
 
-// 色の選択肢を定義するenum
+pub use color::{Color, ColorDepth, ColorParseError, NAMED_COLORS, Palette};
+pub use context::Context;
+pub use icons::{ICONS_ENV_VAR, Icon, IconSet};
+
+// 文字の装飾 (太字や下線など) を表す構造体
 #[derive(
-    Debug, PartialEq, Eq, Hash, Clone, Copy, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
 )]
-pub enum Color {
-    Red,
-    Green,
-    Yellow,
-    Blue,
-    Magenta,
-    Cyan,
-    White,
-    Black,
-    Rgb(u8, u8, u8),
-}
-
-// --- 手動シリアライズの実装 ---
-impl Serialize for Color {
+pub struct Style {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub const NONE: Style = Style {
+        bold: false,
+        dim: false,
+        italic: false,
+        underline: false,
+        blink: false,
+        reverse: false,
+    };
+    pub const BOLD: Style = Style {
+        bold: true,
+        ..Style::NONE
+    };
+    pub const DIM: Style = Style {
+        dim: true,
+        ..Style::NONE
+    };
+
+    pub fn is_empty(&self) -> bool {
+        *self == Style::NONE
+    }
+
+    // (属性名, 有効か, 開始のSGRコード, 解除のSGRコード)
+    fn attributes(&self) -> [(&'static str, bool, &'static str, &'static str); 6] {
+        [
+            ("bold", self.bold, "1", "22"),
+            ("dim", self.dim, "2", "22"),
+            ("italic", self.italic, "3", "23"),
+            ("underline", self.underline, "4", "24"),
+            ("blink", self.blink, "5", "25"),
+            ("reverse", self.reverse, "7", "27"),
+        ]
+    }
+
+    /// 有効な属性を開始するSGRパラメータの一覧
+    pub fn sgr_codes(&self) -> Vec<&'static str> {
+        self.attributes()
+            .into_iter()
+            .filter(|(_, on, _, _)| *on)
+            .map(|(_, _, code, _)| code)
+            .collect()
+    }
+
+    /// 有効な属性だけを解除するSGRパラメータの一覧。
+    /// 太字と薄字はどちらも22で解除されるので重複させない。
+    pub fn reset_codes(&self) -> Vec<&'static str> {
+        let mut codes: Vec<&'static str> = Vec::new();
+        for (_, on, _, reset) in self.attributes() {
+            if on && !codes.contains(&reset) {
+                codes.push(reset);
+            }
+        }
+        codes
+    }
+}
+
+impl Serialize for Style {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        // Displayトレイトの実装を利用して文字列としてシリアライズ
         serializer.serialize_str(&self.to_string())
     }
 }
 
-// --- 手動デシリアライズの実装 ---
-impl<'de> Deserialize<'de> for Color {
+impl<'de> Deserialize<'de> for Style {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Color::from_str(&s).map_err(de::Error::custom)
+        Style::from_str(&s).map_err(de::Error::custom)
     }
 }
 
-impl FromStr for Color {
+/// `bold,underline` のようにカンマ区切りで属性を並べる。`none` または空文字で装飾なし。
+impl FromStr for Style {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "red" => Ok(Color::Red),
-            "green" => Ok(Color::Green),
-            "yellow" => Ok(Color::Yellow),
-            "blue" => Ok(Color::Blue),
-            "magenta" => Ok(Color::Magenta),
-            "cyan" => Ok(Color::Cyan),
-            "white" => Ok(Color::White),
-            "black" => Ok(Color::Black),
-            _ => {
-                if s.starts_with('#') && (s.len() == 7 || s.len() == 4) {
-                    if s.len() == 7 {
-                        let r = u8::from_str_radix(&s[1..3], 16).map_err(|_| "Invalid hex R")?;
-                        let g = u8::from_str_radix(&s[3..5], 16).map_err(|_| "Invalid hex G")?;
-                        let b = u8::from_str_radix(&s[5..7], 16).map_err(|_| "Invalid hex B")?;
-                        Ok(Color::Rgb(r, g, b))
-                    } else {
-                        // #FFF 形式のサポート
-                        let r = u8::from_str_radix(&s[1..2].repeat(2), 16)
-                            .map_err(|_| "Invalid hex R")?;
-                        let g = u8::from_str_radix(&s[2..3].repeat(2), 16)
-                            .map_err(|_| "Invalid hex G")?;
-                        let b = u8::from_str_radix(&s[3..4].repeat(2), 16)
-                            .map_err(|_| "Invalid hex B")?;
-                        Ok(Color::Rgb(r, g, b))
-                    }
-                } else {
-                    Err(format!("Invalid color name or hex format: {}", s))
-                }
+        let mut style = Style::default();
+        for attr in s.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            match attr.to_lowercase().as_str() {
+                "none" => {}
+                "bold" => style.bold = true,
+                "dim" => style.dim = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "blink" => style.blink = true,
+                "reverse" => style.reverse = true,
+                _ => return Err(format!("Invalid style attribute: {}", attr)),
             }
         }
+        Ok(style)
     }
 }
 
-impl fmt::Display for Color {
+impl fmt::Display for Style {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Color::Red => write!(f, "red"),
-            Color::Green => write!(f, "green"),
-            Color::Yellow => write!(f, "yellow"),
-            Color::Blue => write!(f, "blue"),
-            Color::Magenta => write!(f, "magenta"),
-            Color::Cyan => write!(f, "cyan"),
-            Color::White => write!(f, "white"),
-            Color::Black => write!(f, "black"),
-            Color::Rgb(r, g, b) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+        let names: Vec<&str> = self
+            .attributes()
+            .into_iter()
+            .filter(|(_, on, _, _)| *on)
+            .map(|(name, _, _, _)| name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
         }
     }
 }
 
-impl Color {
-    pub fn as_ansi_code(&self) -> String {
-        match self {
-            Color::Red => "31".to_string(),
-            Color::Green => "32".to_string(),
-            Color::Yellow => "33".to_string(),
-            Color::Blue => "34".to_string(),
-            Color::Magenta => "35".to_string(),
-            Color::Cyan => "36".to_string(),
-            Color::White => "37".to_string(),
-            Color::Black => "30".to_string(),
-            Color::Rgb(r, g, b) => format!("38;2;{};{};{}", r, g, b),
+// セグメント同士の区切り方を定義するenum
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Separator {
+    /// A single space
+    #[default]
+    Space,
+    /// Powerline arrow ( / ▶ / >)
+    Powerline,
+    /// Rounded cap ( / ◗ / ))
+    Round,
+    /// Slanted edge ( / ◢ / /)
+    Slant,
+}
+
+impl Separator {
+    /// 背景色が切り替わる位置に描く記号。`Space` は記号を持たない。
+    pub fn glyph(&self, icons: IconSet) -> Option<&'static str> {
+        let icon = match self {
+            Separator::Space => return None,
+            Separator::Powerline => Icon::new("\u{e0b0}", "▶", ">"),
+            Separator::Round => Icon::new("\u{e0b4}", "◗", ")"),
+            Separator::Slant => Icon::new("\u{e0bc}", "◢", "/"),
+        };
+        Some(icon.get(icons))
+    }
+}
+
+/// 全モジュール共通の見た目の設定。モジュールが出力した全セグメントに適用される。
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::Args,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
+)]
+pub struct SegmentOptions {
+    /// Background color of the module's segments
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bg: Option<Color>,
+    /// How the module's block is separated from the next one
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub separator: Option<Separator>,
+    /// Text attributes of the module's segments, e.g. `bold,italic`
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<Style>,
+    /// Priority of the module's segments when the prompt is too wide (lower is dropped first)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    /// Format template of the module's output, e.g. `{icon} {branch}{?staged: +{staged}}`
+    #[arg(long, value_parser = crate::template::parse_format_arg)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// アイコンの種類。未指定ならコマンドライン、環境変数、設定ファイルで選んだものになる。
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icons: Option<IconSet>,
+}
+
+impl SegmentOptions {
+    /// 未指定の項目を `base` の値で補う。
+    pub fn merged_with(self, base: &SegmentOptions) -> SegmentOptions {
+        SegmentOptions {
+            bg: self.bg.or_else(|| base.bg.clone()),
+            separator: self.separator.or(base.separator),
+            style: self.style.or(base.style),
+            priority: self.priority.or(base.priority),
+            format: self.format.or_else(|| base.format.clone()),
+            icons: self.icons.or(base.icons),
+        }
+    }
+
+    pub fn apply(&self, segment: &mut PromptSegment) {
+        if segment.background.is_none() {
+            segment.background = self.bg.clone();
+        }
+        if let Some(separator) = self.separator {
+            segment.separator = separator;
+        }
+        if let Some(icons) = self.icons {
+            segment.icons = icons;
+        }
+        // モジュール自身が装飾を決めたセグメント (detached HEAD など) はそのまま残す
+        if let Some(style) = self.style
+            && segment.style.is_empty()
+        {
+            segment.style = style;
+        }
+        if let Some(priority) = self.priority {
+            segment.priority = priority;
         }
     }
 }
-#[derive(Debug, Clone, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+
+// 出力先のシェルを定義するenum
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// Emit escape sequences as-is
+    #[default]
+    Raw,
+    /// Wrap non-printing sequences in `%{ ... %}` for zsh prompts
+    Zsh,
+    /// Print the segments as a JSON array for other tools
+    Json,
+}
+
+/// 特に指定の無いセグメントの優先度
+pub const DEFAULT_PRIORITY: u8 = 100;
+
+fn default_priority() -> u8 {
+    DEFAULT_PRIORITY
+}
+
+#[derive(
+    Debug, Clone, Serialize, Deserialize, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive,
+)]
 pub struct PromptSegment {
     pub content: String,
     pub color: Option<Color>,
+    /// このセグメントを出力したモジュールの名前 (`git` など)
+    pub module: Option<String>,
+    pub background: Option<Color>,
+    /// 右隣のセグメントとの区切り方
+    pub separator: Separator,
+    /// 区切りの記号に使うアイコンの種類
+    #[serde(skip)]
+    pub icons: IconSet,
+    pub style: Style,
+    /// 幅が足りないとき、値の小さいセグメントから省略・削除される
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    /// 幅が足りないときに削除の前に試す短い表示
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub short: Option<String>,
+    /// 直前のセグメントと空白を挟まずに連結する
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub glue: bool,
+}
+
+impl Default for PromptSegment {
+    fn default() -> Self {
+        Self {
+            content: String::new(),
+            color: None,
+            module: None,
+            background: None,
+            separator: Separator::default(),
+            icons: IconSet::default(),
+            style: Style::NONE,
+            priority: DEFAULT_PRIORITY,
+            short: None,
+            glue: false,
+        }
+    }
 }
 
 impl PromptSegment {
     pub fn new(content: String) -> Self {
         Self {
             content,
-            color: None,
+            ..Default::default()
         }
     }
 
     pub fn new_with_color(content: String, color_str: &str) -> Self {
         let color = Color::from_str(color_str).ok();
-        Self { content, color }
+        Self {
+            content,
+            color,
+            ..Default::default()
+        }
+    }
+
+    /// ブランチ名やディレクトリ名など、プログラム外部由来の文字列からセグメントを作る。
+    /// 制御文字はエスケープ表記に置き換えられ、端末に解釈されることはない。
+    pub fn new_untrusted_with_color(content: &str, color_str: &str) -> Self {
+        Self::new_with_color(sanitize_untrusted(content), color_str)
     }
 
     pub fn format(&self) -> String {
+        self.format_content(&self.content, ColorDepth::TrueColor)
+    }
+
+    pub fn format_for(&self, mode: OutputMode) -> String {
+        let depth = ColorDepth::TrueColor;
+        match mode {
+            OutputMode::Zsh => wrap_invisible_for_zsh(&self.format_unwrapped(mode, depth)),
+            _ => self.format_unwrapped(mode, depth),
+        }
+    }
+
+    /// 内容のエスケープだけを行い、`%{ ... %}` での囲みは呼び出し側に任せる。
+    /// 背景色などを含む行全体をまとめて囲むときに使う。
+    pub(crate) fn format_unwrapped(&self, mode: OutputMode, depth: ColorDepth) -> String {
+        match mode {
+            // JSONはセグメントの一覧として出力するので、個々のセグメントはそのまま整形する
+            OutputMode::Raw | OutputMode::Json => self.format_content(&self.content, depth),
+            OutputMode::Zsh => self.format_content(&escape_for_zsh(&self.content), depth),
+        }
+    }
+
+    /// 色と装飾を全て外し、内容だけを残したセグメント
+    pub fn without_styling(&self) -> Self {
+        Self {
+            color: None,
+            background: None,
+            style: Style::NONE,
+            ..self.clone()
+        }
+    }
+
+    /// 装飾付きのセグメントを作る。
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// 優先度を指定したセグメントを作る。
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// 幅が足りないときに使う短い表示を指定したセグメントを作る。
+    /// 外部由来の文字列の場合は、無害化済みの内容から作ること。
+    pub fn with_short(mut self, short: String) -> Self {
+        self.short = Some(short);
+        self
+    }
+
+    fn format_content(&self, content: &str, depth: ColorDepth) -> String {
+        let mut start: Vec<String> = self
+            .style
+            .sgr_codes()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let mut reset: Vec<&str> = Vec::new();
         if let Some(color) = &self.color {
-            format!("\x1b[{}m{}\x1b[39m", color.as_ansi_code(), self.content)
+            start.push(color.as_ansi_code(depth));
+            reset.push("39");
+        }
+        // 設定した属性だけを解除し、背景色など周囲の状態は壊さない
+        reset.extend(self.style.reset_codes());
+
+        if start.is_empty() {
+            content.to_string()
+        } else {
+            format!(
+                "\x1b[{}m{}\x1b[{}m",
+                start.join(";"),
+                content,
+                reset.join(";")
+            )
+        }
+    }
+}
+
+/// 外部由来の文字列を表示用に無害化する。
+/// C0/C1制御文字とDELは `\xNN` 形式に、バックスラッシュ自体は `\\` に置き換える。
+pub fn sanitize_untrusted(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' {
+            out.push_str("\\\\");
+        } else if c.is_control() {
+            out.push_str(&format!("\\x{:02x}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// zshのプロンプト展開で特別な意味を持つ `%` をエスケープする。
+pub fn escape_for_zsh(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// ESC の直後から、エスケープシーケンスの残りを読み進めて `out` に追加する。
+pub(crate) fn take_escape_sequence(chars: &mut Peekable<Chars<'_>>, out: &mut String) {
+    match chars.peek() {
+        // CSI: ESC [ パラメータ... 終端バイト(0x40-0x7e)
+        Some('[') => {
+            out.push(chars.next().unwrap());
+            for c in chars.by_ref() {
+                out.push(c);
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+        // OSC: ESC ] ... (BEL または ST で終端)
+        Some(']') => {
+            out.push(chars.next().unwrap());
+            while let Some(c) = chars.next() {
+                out.push(c);
+                if c == '\x07' {
+                    break;
+                }
+                if c == '\x1b' && chars.peek() == Some(&'\\') {
+                    out.push(chars.next().unwrap());
+                    break;
+                }
+            }
+        }
+        // その他の2バイトシーケンス
+        Some(_) => out.push(chars.next().unwrap()),
+        None => {}
+    }
+}
+
+/// エスケープシーケンスや制御文字など表示幅を持たないバイト列を `%{ ... %}` で囲む。
+/// zsh はこの範囲を幅0として扱うため、カーソル位置の計算がずれなくなる。
+pub fn wrap_invisible_for_zsh(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 8);
+    let mut invisible = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            invisible.push(c);
+            take_escape_sequence(&mut chars, &mut invisible);
+        } else if c.is_control() && c != '\n' {
+            invisible.push(c);
         } else {
-            self.content.clone()
+            if !invisible.is_empty() {
+                out.push_str("%{");
+                out.push_str(&invisible);
+                out.push_str("%}");
+                invisible.clear();
+            }
+            out.push(c);
         }
     }
+    if !invisible.is_empty() {
+        out.push_str("%{");
+        out.push_str(&invisible);
+        out.push_str("%}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_for_zsh_wraps_sgr_sequences() {
+        let segment = PromptSegment::new_with_color("main".to_string(), "yellow");
+        assert_eq!(
+            segment.format_for(OutputMode::Zsh),
+            "%{\x1b[33m%}main%{\x1b[39m%}"
+        );
+        assert_eq!(segment.format_for(OutputMode::Raw), "\x1b[33mmain\x1b[39m");
+    }
+
+    #[test]
+    fn test_wrap_invisible_for_zsh_merges_adjacent_sequences() {
+        assert_eq!(
+            wrap_invisible_for_zsh("\x1b[1m\x1b[31mx\x07"),
+            "%{\x1b[1m\x1b[31m%}x%{\x07%}"
+        );
+        assert_eq!(
+            wrap_invisible_for_zsh("\x1b]0;title\x1b\\ok"),
+            "%{\x1b]0;title\x1b\\%}ok"
+        );
+        assert_eq!(wrap_invisible_for_zsh("a\nb"), "a\nb");
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_encodes_control_characters() {
+        assert_eq!(sanitize_untrusted("a\x1b[31mb"), "a\\x1b[31mb");
+        assert_eq!(sanitize_untrusted("tab\there\u{9b}"), "tab\\x09here\\x9b");
+        assert_eq!(sanitize_untrusted("back\\slash"), "back\\\\slash");
+        assert_eq!(
+            sanitize_untrusted("日本語/ディレクトリ"),
+            "日本語/ディレクトリ"
+        );
+    }
+
+    #[test]
+    fn test_untrusted_segment_cannot_inject_prompt_escapes() {
+        let segment =
+            PromptSegment::new_untrusted_with_color("%F{red}$(id)\x1b]0;pwned\x07", "cyan");
+        let zsh = segment.format_for(OutputMode::Zsh);
+        assert_eq!(
+            zsh,
+            "%{\x1b[36m%}%%F{red}$(id)\\x1b]0;pwned\\x07%{\x1b[39m%}"
+        );
+        // 囲み以外にESCが残っていないこと
+        assert_eq!(zsh.matches('\x1b').count(), 2);
+    }
+
+    #[test]
+    fn test_style_parse_and_display() {
+        let style = Style::from_str("Bold, underline").unwrap();
+        assert!(style.bold && style.underline && !style.dim);
+        assert_eq!(style.to_string(), "bold,underline");
+        assert!(Style::from_str("none").unwrap().is_empty());
+        assert!(Style::from_str("").unwrap().is_empty());
+        assert!(Style::from_str("bold,sparkly").is_err());
+    }
+
+    #[test]
+    fn test_styled_segment_resets_only_its_attributes() {
+        let segment = PromptSegment::new_with_color("main".to_string(), "red")
+            .with_style(Style::from_str("bold,dim,reverse").unwrap());
+        assert_eq!(
+            segment.format_for(OutputMode::Raw),
+            "\x1b[1;2;7;31mmain\x1b[39;22;27m"
+        );
+
+        let plain = PromptSegment::new("x".to_string()).with_style(Style::DIM);
+        assert_eq!(plain.format_for(OutputMode::Raw), "\x1b[2mx\x1b[22m");
+    }
 }
@@ -1,29 +1,48 @@
-use crate::modules::{Color, PromptSegment};
-use chrono::Utc;
-use std::env;
+use crate::modules::{Color, Context, Icon, IconSet, PromptSegment};
+use crate::template::Template;
+use chrono::{DateTime, Local, Utc};
 
+/// `--format` の書式で使えるフィールド
+/// (`status` は成功時に `0` になるので、条件付きにすると失敗時だけ表示される)
+pub const FIELDS: &[&str] = &["icon", "duration", "status"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{icon}{?duration: {duration}}{?status: {status}}";
+
+// アイコンの定義 (Nerd Font / Unicode / ASCII)
+const SUCCESS_ICON: Icon = Icon::new("\u{f058}", "✓", "ok");
+const FAILURE_ICON: Icon = Icon::new("\u{f071}", "✗", "x");
+/// 終了ステータスが分かる前 (評価が間に合わなかったとき) のアイコン
+pub const COMMAND_ICON: Icon = Icon::new("\u{f120}", "›", ">");
+
+// 引数はCLIのフラグにそのまま対応している
+#[allow(clippy::too_many_arguments)]
 pub fn get_execution_info(
-    last_status_var_name: &String,
+    last_status_var_name: &str,
     last_command_executed_var_name: &Option<String>,
+    context: &Context,
     color: Option<Color>,
-) -> PromptSegment {
+    success_color: Option<Color>,
+    failure_color: Option<Color>,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
     // 1. 環境変数名からステータスを取得
-    let last_status: i32 = env::var(last_status_var_name)
-        .ok()
+    let last_status: i32 = context
+        .var(last_status_var_name)
         .and_then(|val| val.parse().ok())
         .unwrap_or(0);
     let last_command_executed: Option<f64> = last_command_executed_var_name
         .as_ref()
-        .and_then(|name| env::var(name).ok().and_then(|val| val.parse().ok()));
+        .and_then(|name| context.var(name).and_then(|val| val.parse().ok()));
     let status_icon: &str;
     let segment_color: Color;
 
     if last_status == 0 {
-        status_icon = "";
-        segment_color = Color::Green;
+        status_icon = SUCCESS_ICON.get(icons);
+        segment_color = success_color.unwrap_or(Color::Green);
     } else {
-        status_icon = "";
-        segment_color = Color::Red;
+        status_icon = FAILURE_ICON.get(icons);
+        segment_color = failure_color.unwrap_or(Color::Red);
     }
 
     let mut duration_str = String::new();
@@ -56,17 +75,60 @@ pub fn get_execution_info(
         }
     }
 
-    let mut info = if !duration_str.is_empty() {
-        format!("{} {}", status_icon, duration_str)
-    } else {
-        status_icon.to_string()
-    };
+    let color = color.unwrap_or(segment_color).to_string();
+    let field = |content: String| PromptSegment::new_with_color(content, &color);
+    let fields = [
+        ("icon", field(status_icon.to_string())),
+        ("duration", field(duration_str)),
+        ("status", field(last_status.to_string())),
+    ];
+
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &field(String::new()))
+}
 
-    if last_status != 0 {
-        info = format!("{} {}", info, last_status);
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_execution_info(
+    last_status_var_name: &str,
+    last_command_executed_var_name: &Option<String>,
+    context: &Context,
+) -> Vec<(&'static str, String)> {
+    let raw = |name: &str| match context.var(name) {
+        Some(value) => format!("${} = {:?}", name, value),
+        None => format!("${} is not set", name),
+    };
+    let mut status = raw(last_status_var_name);
+    if context
+        .var(last_status_var_name)
+        .and_then(|val| val.parse::<i32>().ok())
+        .is_none()
+    {
+        status.push_str(" (treated as 0)");
     }
 
-    PromptSegment::new_with_color(info, &color.unwrap_or(segment_color).to_string())
+    let start = match last_command_executed_var_name {
+        Some(name) => {
+            let mut start = raw(name);
+            match context.var(name).and_then(|val| val.parse::<f64>().ok()) {
+                Some(timestamp) => {
+                    let now = Utc::now().timestamp_nanos_opt().unwrap() as f64 / 1_000_000_000.0;
+                    if let Some(time) = DateTime::from_timestamp(
+                        timestamp.trunc() as i64,
+                        (timestamp.fract() * 1_000_000_000.0) as u32,
+                    ) {
+                        start.push_str(&format!(
+                            " ({}, {:.3}s ago)",
+                            time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S%.3f"),
+                            now - timestamp
+                        ));
+                    }
+                }
+                None => start.push_str(" (no duration shown)"),
+            }
+            start
+        }
+        None => "not configured (--last-command-executed)".to_string(),
+    };
+    vec![("status", status), ("start", start)]
 }
 
 #[cfg(test)]
@@ -88,7 +150,16 @@ mod tests {
         set_test_env(&status_var, "0");
 
         // 引数を参照 (&) で渡すように修正
-        let result = get_execution_info(&status_var, &None, None);
+        let result = &get_execution_info(
+            &status_var,
+            &None,
+            &Context::current(),
+            None,
+            None,
+            None,
+            IconSet::Nerd,
+            None,
+        )[0];
         assert_eq!(result.content, "");
         assert_eq!(result.color, Some(Color::Green));
     }
@@ -103,7 +174,16 @@ mod tests {
         set_test_env(&time_var, (now_f64 - 1.2).to_string().as_str());
 
         // 引数を参照 (&) で渡すように修正
-        let result = get_execution_info(&status_var, &Some(time_var), None);
+        let result = &get_execution_info(
+            &status_var,
+            &Some(time_var),
+            &Context::current(),
+            None,
+            None,
+            None,
+            IconSet::Nerd,
+            None,
+        )[0];
 
         assert!(result.content.contains(""));
         assert!(result.content.contains("127"));
@@ -120,7 +200,16 @@ mod tests {
         let now_f64 = Utc::now().timestamp_nanos_opt().unwrap() as f64 / 1_000_000_000.0;
         set_test_env(&time_var, (now_f64 - 150.5).to_string().as_str());
 
-        let result = get_execution_info(&status_var, &Some(time_var), None);
+        let result = &get_execution_info(
+            &status_var,
+            &Some(time_var),
+            &Context::current(),
+            None,
+            None,
+            None,
+            IconSet::Nerd,
+            None,
+        )[0];
         assert!(result.content.contains("2m30s"));
     }
 
@@ -129,7 +218,16 @@ mod tests {
         let status_var = "NON_EXISTENT_VAR".to_string();
         let time_var = Some("INVALID_VAL_VAR".to_string());
 
-        let result = get_execution_info(&status_var, &time_var, None);
+        let result = &get_execution_info(
+            &status_var,
+            &time_var,
+            &Context::current(),
+            None,
+            None,
+            None,
+            IconSet::Nerd,
+            None,
+        )[0];
 
         assert_eq!(result.content, "");
         assert_eq!(result.color, Some(Color::Green));
@@ -141,9 +239,78 @@ mod tests {
         set_test_env(&status_var, "0");
 
         let custom_color = Some(Color::Blue);
-        let result = get_execution_info(&status_var, &None, custom_color);
+        let result = &get_execution_info(
+            &status_var,
+            &None,
+            &Context::current(),
+            custom_color,
+            None,
+            None,
+            IconSet::Nerd,
+            None,
+        )[0];
 
         assert_eq!(result.content, "");
         assert_eq!(result.color, Some(Color::Blue));
     }
+
+    #[test]
+    fn test_get_execution_info_with_format() {
+        let status_var = "TEST_STATUS_FORMAT".to_string();
+        set_test_env(&status_var, "2");
+        let result = get_execution_info(
+            &status_var,
+            &None,
+            &Context::current(),
+            None,
+            Some(Color::Green),
+            Some(Color::Yellow),
+            IconSet::Nerd,
+            Some("{?status:exit {status}}"),
+        );
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].content, "exit 2");
+        assert_eq!(result[0].color, Some(Color::Yellow));
+
+        // 成功時は何も表示しない
+        set_test_env(&status_var, "0");
+        let result = get_execution_info(
+            &status_var,
+            &None,
+            &Context::current(),
+            None,
+            None,
+            None,
+            IconSet::Nerd,
+            Some("{?status:exit {status}}"),
+        );
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_explain_execution_info_shows_raw_values() {
+        let context = Context {
+            vars: [
+                ("STATUS".to_string(), "oops".to_string()),
+                ("STARTED".to_string(), "1700000000.5".to_string()),
+            ]
+            .into(),
+            ..Context::default()
+        };
+        let explanation = explain_execution_info("STATUS", &Some("STARTED".to_string()), &context);
+        assert_eq!(explanation[0].0, "status");
+        assert_eq!(explanation[0].1, "$STATUS = \"oops\" (treated as 0)");
+        assert_eq!(explanation[1].0, "start");
+        assert!(
+            explanation[1]
+                .1
+                .starts_with("$STARTED = \"1700000000.5\" (2023-11-1")
+        );
+        assert!(explanation[1].1.ends_with("s ago)"));
+
+        // 開始時刻の変数を渡していなければ、その旨を示す
+        let explanation = explain_execution_info("MISSING", &None, &context);
+        assert_eq!(explanation[0].1, "$MISSING is not set (treated as 0)");
+        assert_eq!(explanation[1].1, "not configured (--last-command-executed)");
+    }
 }
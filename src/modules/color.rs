@@ -0,0 +1,738 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// 色の選択肢を定義するenum
+#[derive(Debug, PartialEq, Eq, Hash, Clone, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+pub enum Color {
+    /// 端末の既定の色
+    Default,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Black,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    BrightBlack,
+    /// 256色パレットの番号
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+    /// 設定ファイルの `[palette]` の色 (`@name`)。
+    /// 設定を読み込んだ後に `resolve` で実際の色に置き換える。
+    Palette(String),
+}
+
+// 16色パレットの並び (SGRのコード順) と、xtermでの既定のRGB値
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::White, (229, 229, 229)),
+    (Color::BrightBlack, (127, 127, 127)),
+    (Color::BrightRed, (255, 0, 0)),
+    (Color::BrightGreen, (0, 255, 0)),
+    (Color::BrightYellow, (255, 255, 0)),
+    (Color::BrightBlue, (92, 92, 255)),
+    (Color::BrightMagenta, (255, 0, 255)),
+    (Color::BrightCyan, (0, 255, 255)),
+    (Color::BrightWhite, (255, 255, 255)),
+];
+
+// 256色パレットの 6x6x6 キューブで使われる各チャンネルの値
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// --- 手動シリアライズの実装 ---
+impl Serialize for Color {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Displayトレイトの実装を利用して文字列としてシリアライズ
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+// --- 手動デシリアライズの実装 ---
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Color::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// `@name` で参照できる色の一覧
+pub type Palette = BTreeMap<String, Color>;
+
+/// 色の指定を解釈できなかった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColorParseError {
+    Empty,
+    UnknownName(String),
+    /// `#` の後が3桁または6桁の16進数ではない
+    InvalidHex(String),
+    /// パレット番号が0-255の範囲外
+    IndexOutOfRange(String),
+    /// `rgb(...)` / `hsl(...)` の書式が正しくない
+    InvalidFunction(String),
+    /// `rgb(...)` / `hsl(...)` の値が範囲外
+    ComponentOutOfRange(String),
+    /// `@name` がパレットに登録されていない
+    UnknownPaletteColor(String),
+    /// `@` の後の名前が空か、英数字・`-`・`_` 以外を含む
+    InvalidPaletteName(String),
+}
+
+impl fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorParseError::Empty => write!(f, "Empty color"),
+            ColorParseError::UnknownName(s) => write!(f, "Unknown color name: {}", s),
+            ColorParseError::InvalidHex(s) => {
+                write!(f, "Invalid hex color (expected #rgb or #rrggbb): {}", s)
+            }
+            ColorParseError::IndexOutOfRange(s) => {
+                write!(f, "Color index out of range (expected 0-255): {}", s)
+            }
+            ColorParseError::InvalidFunction(s) => write!(
+                f,
+                "Invalid color function (expected rgb(r, g, b) or hsl(h, s%, l%)): {}",
+                s
+            ),
+            ColorParseError::ComponentOutOfRange(s) => {
+                write!(f, "Color component out of range: {}", s)
+            }
+            ColorParseError::UnknownPaletteColor(name) => {
+                write!(f, "Unknown palette color: @{}", name)
+            }
+            ColorParseError::InvalidPaletteName(s) => write!(
+                f,
+                "Invalid palette color (expected @ followed by letters, digits, - or _): {}",
+                s
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ColorParseError {}
+
+/// 色を解釈する。受け付ける書式:
+/// `red` / `bright-red` / `default`、`0`-`255`、`#rgb` / `#rrggbb`、
+/// `rgb(255, 128, 0)` / `rgb(100%, 50%, 0%)`、`hsl(30, 100%, 50%)`、`@name`
+///
+/// `@name` はパレットを引かずに `Color::Palette` として残す。
+impl FromStr for Color {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(ColorParseError::Empty);
+        }
+        if let Some(name) = s.strip_prefix('@') {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(ColorParseError::InvalidPaletteName(s.to_string()));
+            }
+            return Ok(Color::Palette(name.to_string()));
+        }
+        if let Some(hex) = s.strip_prefix('#') {
+            return parse_hex(hex).ok_or_else(|| ColorParseError::InvalidHex(s.to_string()));
+        }
+
+        let lower = s.to_ascii_lowercase();
+        if let Some(args) = function_args(&lower, "rgb") {
+            return parse_rgb(args).map_err(|e| e.with_input(s));
+        }
+        if let Some(args) = function_args(&lower, "hsl") {
+            return parse_hsl(args).map_err(|e| e.with_input(s));
+        }
+        if lower.bytes().all(|b| b.is_ascii_digit()) {
+            return lower
+                .parse::<u8>()
+                .map(Color::Indexed)
+                .map_err(|_| ColorParseError::IndexOutOfRange(s.to_string()));
+        }
+        named_color(&lower.replace('_', "-"))
+            .ok_or_else(|| ColorParseError::UnknownName(s.to_string()))
+    }
+}
+
+impl Color {
+    /// 色を解釈し、`@name` を `palette` の色に置き換える
+    pub fn parse_with_palette(s: &str, palette: &Palette) -> Result<Color, ColorParseError> {
+        let mut color = s.parse::<Color>()?;
+        color.resolve(palette)?;
+        Ok(color)
+    }
+
+    /// `@name` を `palette` の色に置き換える。
+    /// パレットに無い名前はそのまま残り、端末の既定の色で表示される。
+    pub fn resolve(&mut self, palette: &Palette) -> Result<(), ColorParseError> {
+        let Color::Palette(name) = self else {
+            return Ok(());
+        };
+        // パレットの中で別のパレットの色は参照できない
+        match palette.get(name.as_str()) {
+            Some(color) if !matches!(color, Color::Palette(_)) => {
+                *self = color.clone();
+                Ok(())
+            }
+            _ => Err(ColorParseError::UnknownPaletteColor(name.clone())),
+        }
+    }
+}
+
+impl ColorParseError {
+    // 関数形式の内側で見つかった誤りに、入力全体を添える
+    fn with_input(self, input: &str) -> ColorParseError {
+        match self {
+            ColorParseError::ComponentOutOfRange(_) => {
+                ColorParseError::ComponentOutOfRange(input.to_string())
+            }
+            _ => ColorParseError::InvalidFunction(input.to_string()),
+        }
+    }
+}
+
+/// 名前で指定できる色。`bright_red` のような `_` 区切りも受け付ける。
+pub const NAMED_COLORS: [(&str, Color); 17] = [
+    ("default", Color::Default),
+    ("red", Color::Red),
+    ("green", Color::Green),
+    ("yellow", Color::Yellow),
+    ("blue", Color::Blue),
+    ("magenta", Color::Magenta),
+    ("cyan", Color::Cyan),
+    ("white", Color::White),
+    ("black", Color::Black),
+    ("bright-red", Color::BrightRed),
+    ("bright-green", Color::BrightGreen),
+    ("bright-yellow", Color::BrightYellow),
+    ("bright-blue", Color::BrightBlue),
+    ("bright-magenta", Color::BrightMagenta),
+    ("bright-cyan", Color::BrightCyan),
+    ("bright-white", Color::BrightWhite),
+    ("bright-black", Color::BrightBlack),
+];
+
+fn named_color(name: &str) -> Option<Color> {
+    NAMED_COLORS
+        .iter()
+        .find(|(named, _)| *named == name)
+        .map(|(_, color)| color.clone())
+}
+
+// `#` を除いた16進数部分。ASCII以外の文字を含む場合はバイト位置で切り出す前に弾く。
+fn parse_hex(hex: &str) -> Option<Color> {
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok();
+    match hex.len() {
+        // #FFF 形式のサポート
+        3 => Some(Color::Rgb(digit(0)? * 17, digit(1)? * 17, digit(2)? * 17)),
+        6 => {
+            let pair = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            Some(Color::Rgb(pair(0)?, pair(2)?, pair(4)?))
+        }
+        _ => None,
+    }
+}
+
+// `rgb(...)` の括弧の内側を取り出す
+fn function_args<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    s.strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+// カンマまたは空白で区切られた3つの値
+fn components(args: &str) -> Result<[&str; 3], ColorParseError> {
+    let parts: Vec<&str> = args
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+        .collect();
+    parts
+        .try_into()
+        .map_err(|_| ColorParseError::InvalidFunction(args.to_string()))
+}
+
+// 有限の数値だけを受け付ける (`inf` や `nan` は不正な書式として扱う)
+fn number(s: &str) -> Result<f64, ColorParseError> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(ColorParseError::InvalidFunction(s.to_string())),
+    }
+}
+
+fn in_range(v: f64, max: f64, s: &str) -> Result<f64, ColorParseError> {
+    if (0.0..=max).contains(&v) {
+        Ok(v)
+    } else {
+        Err(ColorParseError::ComponentOutOfRange(s.to_string()))
+    }
+}
+
+fn parse_rgb(args: &str) -> Result<Color, ColorParseError> {
+    let channel = |s: &str| -> Result<u8, ColorParseError> {
+        let value = match s.strip_suffix('%') {
+            Some(percent) => in_range(number(percent)?, 100.0, s)? / 100.0 * 255.0,
+            None => in_range(number(s)?, 255.0, s)?,
+        };
+        Ok(value.round() as u8)
+    };
+    let [r, g, b] = components(args)?;
+    Ok(Color::Rgb(channel(r)?, channel(g)?, channel(b)?))
+}
+
+fn parse_hsl(args: &str) -> Result<Color, ColorParseError> {
+    let percent = |s: &str| -> Result<f64, ColorParseError> {
+        Ok(in_range(number(s.strip_suffix('%').unwrap_or(s))?, 100.0, s)? / 100.0)
+    };
+    let [h, s, l] = components(args)?;
+    let hue = number(h.strip_suffix("deg").unwrap_or(h))?.rem_euclid(360.0);
+    let (s, l) = (percent(s)?, percent(l)?);
+
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = l - chroma / 2.0;
+    let channel = |v: f64| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    Ok(Color::Rgb(channel(r), channel(g), channel(b)))
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::Default => write!(f, "default"),
+            Color::Red => write!(f, "red"),
+            Color::Green => write!(f, "green"),
+            Color::Yellow => write!(f, "yellow"),
+            Color::Blue => write!(f, "blue"),
+            Color::Magenta => write!(f, "magenta"),
+            Color::Cyan => write!(f, "cyan"),
+            Color::White => write!(f, "white"),
+            Color::Black => write!(f, "black"),
+            Color::BrightRed => write!(f, "bright-red"),
+            Color::BrightGreen => write!(f, "bright-green"),
+            Color::BrightYellow => write!(f, "bright-yellow"),
+            Color::BrightBlue => write!(f, "bright-blue"),
+            Color::BrightMagenta => write!(f, "bright-magenta"),
+            Color::BrightCyan => write!(f, "bright-cyan"),
+            Color::BrightWhite => write!(f, "bright-white"),
+            Color::BrightBlack => write!(f, "bright-black"),
+            Color::Indexed(index) => write!(f, "{}", index),
+            Color::Rgb(r, g, b) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            Color::Palette(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+impl Color {
+    /// 前景色のSGRパラメータ。端末が扱えない色は `depth` に合わせて近い色に落とす。
+    pub fn as_ansi_code(&self, depth: ColorDepth) -> String {
+        match (self, depth) {
+            // 色を使わない場合も呼び出し側が壊れないように既定の色を返す
+            (Color::Default | Color::Palette(_), _) | (_, ColorDepth::NoColor) => "39".to_string(),
+            (Color::Rgb(r, g, b), ColorDepth::TrueColor | ColorDepth::Auto) => {
+                format!("38;2;{};{};{}", r, g, b)
+            }
+            (Color::Rgb(r, g, b), ColorDepth::Ansi256) => {
+                format!("38;5;{}", nearest_indexed(*r, *g, *b))
+            }
+            (
+                Color::Indexed(index),
+                ColorDepth::TrueColor | ColorDepth::Ansi256 | ColorDepth::Auto,
+            ) => {
+                format!("38;5;{}", index)
+            }
+            (Color::Rgb(..) | Color::Indexed(_), ColorDepth::Ansi16) => {
+                let (r, g, b) = self.to_rgb();
+                nearest_ansi16(r, g, b).as_ansi_code(depth)
+            }
+            _ => {
+                let index = ANSI16.iter().position(|(c, _)| c == self).unwrap_or(0);
+                // 通常色は30-37、明るい色は90-97
+                if index < 8 {
+                    (30 + index).to_string()
+                } else {
+                    (90 + index - 8).to_string()
+                }
+            }
+        }
+    }
+
+    pub fn as_ansi_bg_code(&self, depth: ColorDepth) -> String {
+        let code = self.as_ansi_code(depth);
+        match code.strip_prefix("38;") {
+            Some(rest) => format!("48;{}", rest),
+            // 1つの数値で表す色は前景色のコードに10を足すと背景色になる
+            None => (code.parse::<u8>().unwrap_or(39) + 10).to_string(),
+        }
+    }
+
+    // 色のおおよそのRGB値。既定の色は分からないので白として扱う。
+    fn to_rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            Color::Rgb(r, g, b) => (r, g, b),
+            Color::Indexed(index @ 0..=15) => ANSI16[index as usize].1,
+            Color::Indexed(index @ 16..=231) => {
+                let i = (index - 16) as usize;
+                (
+                    CUBE_LEVELS[i / 36],
+                    CUBE_LEVELS[(i / 6) % 6],
+                    CUBE_LEVELS[i % 6],
+                )
+            }
+            Color::Indexed(index) => {
+                let level = 8 + (index - 232) * 10;
+                (level, level, level)
+            }
+            Color::Default | Color::Palette(_) => ANSI16[7].1,
+            _ => ANSI16
+                .iter()
+                .find(|(c, _)| c == self)
+                .map(|(_, rgb)| *rgb)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).unsigned_abs().pow(2);
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+// 256色パレットのうち、色キューブとグレースケールから最も近い番号を選ぶ
+fn nearest_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs())
+            .unwrap_or(0)
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (16 + 36 * ri + 6 * gi + bi) as u8;
+    let cube_rgb = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_step = (average.saturating_sub(8) / 10).min(23) as u8;
+    let gray = 232 + gray_step;
+    let gray_level = 8 + gray_step * 10;
+
+    if distance((r, g, b), (gray_level, gray_level, gray_level)) < distance((r, g, b), cube_rgb) {
+        gray
+    } else {
+        cube
+    }
+}
+
+// 16色パレットから最も近い色を選ぶ
+fn nearest_ansi16(r: u8, g: u8, b: u8) -> Color {
+    ANSI16
+        .iter()
+        .min_by_key(|(_, rgb)| distance((r, g, b), *rgb))
+        .map(|(c, _)| c.clone())
+        .unwrap_or(Color::White)
+}
+
+// 端末が表示できる色数を定義するenum
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorDepth {
+    /// Detect from `COLORTERM` and `TERM`
+    #[default]
+    Auto,
+    /// 24-bit colors
+    #[value(name = "truecolor")]
+    #[serde(rename = "truecolor")]
+    TrueColor,
+    /// The xterm 256-color palette
+    #[value(name = "256")]
+    #[serde(rename = "256")]
+    Ansi256,
+    /// The 16 basic colors
+    #[value(name = "16")]
+    #[serde(rename = "16")]
+    Ansi16,
+    /// No colors or text attributes at all
+    #[value(name = "none")]
+    #[serde(rename = "none")]
+    NoColor,
+}
+
+/// 16色しか表示できない端末の `TERM`
+const LIMITED_TERMS: &[&str] = &["linux", "vt100", "vt220", "ansi", "cons25"];
+
+impl ColorDepth {
+    /// `Auto` を環境変数から判定した実際の色数に置き換える。
+    pub fn resolve(self) -> ColorDepth {
+        match self {
+            ColorDepth::Auto => Self::detect(
+                std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()),
+                std::env::var("COLORTERM").ok().as_deref(),
+                std::env::var("TERM").ok().as_deref(),
+            ),
+            depth => depth,
+        }
+    }
+
+    fn detect(no_color: bool, colorterm: Option<&str>, term: Option<&str>) -> ColorDepth {
+        // https://no-color.org/ : 空でない NO_COLOR があれば色を付けない
+        if no_color || term == Some("dumb") {
+            return ColorDepth::NoColor;
+        }
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            return ColorDepth::TrueColor;
+        }
+        match term {
+            Some(term) if term.contains("direct") => ColorDepth::TrueColor,
+            Some(term) if term.ends_with("-256color") => ColorDepth::Ansi256,
+            // 16色しか扱えないと分かっている端末 (Linuxのコンソールなど)
+            Some(term) if LIMITED_TERMS.contains(&term) => ColorDepth::Ansi16,
+            // それ以外の端末は、今ではほぼ全てが256色に対応している
+            Some(term) if !term.is_empty() => ColorDepth::Ansi256,
+            // 端末の外 (パイプなど) ではこれまで通りそのまま出力する
+            _ => ColorDepth::TrueColor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_downsampling() {
+        let orange = Color::Rgb(0xff, 0x80, 0x00);
+        assert_eq!(orange.as_ansi_code(ColorDepth::TrueColor), "38;2;255;128;0");
+        assert_eq!(orange.as_ansi_code(ColorDepth::Ansi256), "38;5;208");
+        assert_eq!(orange.as_ansi_code(ColorDepth::Ansi16), "33");
+        assert_eq!(orange.as_ansi_bg_code(ColorDepth::Ansi256), "48;5;208");
+        assert_eq!(orange.as_ansi_bg_code(ColorDepth::Ansi16), "43");
+
+        // 灰色はグレースケールの段に寄せる
+        assert_eq!(
+            Color::Rgb(0x80, 0x80, 0x80).as_ansi_code(ColorDepth::Ansi256),
+            "38;5;244"
+        );
+        assert_eq!(Color::Indexed(196).as_ansi_code(ColorDepth::Ansi16), "91");
+        assert_eq!(Color::Indexed(4).as_ansi_code(ColorDepth::Ansi16), "34");
+        assert_eq!(Color::Default.as_ansi_bg_code(ColorDepth::Ansi16), "49");
+        assert_eq!(Color::BrightCyan.as_ansi_code(ColorDepth::TrueColor), "96");
+    }
+
+    #[test]
+    fn test_color_depth_detection() {
+        assert_eq!(
+            ColorDepth::detect(false, Some("truecolor"), Some("xterm-256color")),
+            ColorDepth::TrueColor
+        );
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("screen-256color")),
+            ColorDepth::Ansi256
+        );
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("linux")),
+            ColorDepth::Ansi16
+        );
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("vt100")),
+            ColorDepth::Ansi16
+        );
+        // COLORTERM が無くても、一般的な端末は256色として扱う
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("xterm")),
+            ColorDepth::Ansi256
+        );
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("tmux-256color")),
+            ColorDepth::Ansi256
+        );
+        assert_eq!(ColorDepth::detect(false, None, None), ColorDepth::TrueColor);
+        assert_eq!(
+            ColorDepth::detect(true, Some("truecolor"), Some("xterm-256color")),
+            ColorDepth::NoColor
+        );
+        assert_eq!(
+            ColorDepth::detect(false, None, Some("dumb")),
+            ColorDepth::NoColor
+        );
+    }
+
+    #[test]
+    fn test_new_color_names_roundtrip() {
+        for name in ["default", "bright-magenta", "208", "#102030"] {
+            assert_eq!(Color::from_str(name).unwrap().to_string(), name);
+        }
+    }
+
+    // 乱数生成器 (xorshift64)。失敗を再現できるように固定のシードから始める。
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn test_palette() -> Palette {
+        Palette::from([("accent".to_string(), Color::Indexed(208))])
+    }
+
+    #[test]
+    fn test_parse_color_formats() {
+        let palette = test_palette();
+        let parse = |s: &str| Color::parse_with_palette(s, &palette);
+        assert_eq!(parse("Bright_Red"), Ok(Color::BrightRed));
+        assert_eq!(parse(" #F80 "), Ok(Color::Rgb(0xff, 0x88, 0x00)));
+        assert_eq!(parse("rgb(255, 128, 0)"), Ok(Color::Rgb(255, 128, 0)));
+        assert_eq!(parse("RGB(100% 50% 0%)"), Ok(Color::Rgb(255, 128, 0)));
+        assert_eq!(parse("hsl(120, 100%, 25%)"), Ok(Color::Rgb(0, 128, 0)));
+        assert_eq!(parse("hsl(-120deg, 100%, 50%)"), Ok(Color::Rgb(0, 0, 255)));
+        assert_eq!(parse("hsl(0, 0%, 100%)"), Ok(Color::Rgb(255, 255, 255)));
+        assert_eq!(parse("255"), Ok(Color::Indexed(255)));
+        assert_eq!(parse("@accent"), Ok(Color::Indexed(208)));
+        // パレットを引かずに解釈すると名前のまま残る
+        assert_eq!(
+            Color::from_str("@accent"),
+            Ok(Color::Palette("accent".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_color_errors() {
+        let palette = test_palette();
+        let parse = |s: &str| Color::parse_with_palette(s, &palette);
+        assert_eq!(parse("  "), Err(ColorParseError::Empty));
+        assert_eq!(
+            parse("#a\u{e9}"),
+            Err(ColorParseError::InvalidHex("#a\u{e9}".into()))
+        );
+        assert_eq!(
+            parse("#12345"),
+            Err(ColorParseError::InvalidHex("#12345".into()))
+        );
+        assert_eq!(
+            parse("256"),
+            Err(ColorParseError::IndexOutOfRange("256".into()))
+        );
+        assert_eq!(
+            parse("rgb(1, 2)"),
+            Err(ColorParseError::InvalidFunction("rgb(1, 2)".into()))
+        );
+        assert_eq!(
+            parse("rgb(1, 2, nan)"),
+            Err(ColorParseError::InvalidFunction("rgb(1, 2, nan)".into()))
+        );
+        assert_eq!(
+            parse("rgb(300, 0, 0)"),
+            Err(ColorParseError::ComponentOutOfRange(
+                "rgb(300, 0, 0)".into()
+            ))
+        );
+        assert_eq!(
+            parse("hsl(0, 150%, 50%)"),
+            Err(ColorParseError::ComponentOutOfRange(
+                "hsl(0, 150%, 50%)".into()
+            ))
+        );
+        assert_eq!(
+            parse("@missing"),
+            Err(ColorParseError::UnknownPaletteColor("missing".into()))
+        );
+        // パレットの名前の誤りは、パレットを引く前に解釈の時点で分かる
+        assert_eq!(
+            Color::from_str("@"),
+            Err(ColorParseError::InvalidPaletteName("@".into()))
+        );
+        assert_eq!(
+            Color::from_str("@my color"),
+            Err(ColorParseError::InvalidPaletteName("@my color".into()))
+        );
+        assert_eq!(
+            parse("purple"),
+            Err(ColorParseError::UnknownName("purple".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_color_never_panics_on_random_input() {
+        let alphabet: Vec<char> =
+            "#0123456789abcdefABCDEF(),%. -_@rgbhsldeg\u{e9}\u{65e5}\u{1f600}\0\x1b"
+                .chars()
+                .collect();
+        let palette = test_palette();
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20_000 {
+            let len = (rng.next() % 16) as usize;
+            let input: String = (0..len)
+                .map(|_| alphabet[rng.next() as usize % alphabet.len()])
+                .collect();
+            if let Ok(color) = Color::parse_with_palette(&input, &palette) {
+                // 解釈できた色は、表示形式から同じ色に戻せること
+                assert_eq!(
+                    Color::parse_with_palette(&color.to_string(), &palette),
+                    Ok(color),
+                    "input: {:?}",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_color_never_panics_on_mutated_input() {
+        let seeds = [
+            "#a1b2c3",
+            "#abc",
+            "rgb(10, 20, 30)",
+            "hsl(210deg, 40%, 60%)",
+            "bright-blue",
+            "@accent",
+            "128",
+        ];
+        let palette = test_palette();
+        for seed in seeds {
+            // 全ての文字境界で切り詰めたものと、マルチバイト文字を差し込んだもの
+            for (i, _) in seed.char_indices().chain([(seed.len(), ' ')]) {
+                let _ = Color::parse_with_palette(&seed[..i], &palette);
+                for c in ['\u{e9}', '\u{1f600}', '%', ')', '\0'] {
+                    let mutated = format!("{}{}{}", &seed[..i], c, &seed[i..]);
+                    let _ = Color::parse_with_palette(&mutated, &palette);
+                }
+            }
+        }
+    }
+}
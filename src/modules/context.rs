@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// モジュールを評価するカレントディレクトリと環境変数。
+/// デーモンはクライアントから受け取ったものを使い、プロセス全体の状態は書き換えない。
+#[derive(Debug, Clone, Default, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+pub struct Context {
+    #[rkyv(with = rkyv::with::Map<rkyv::with::AsString>)]
+    pub cwd: Option<PathBuf>,
+    pub vars: BTreeMap<String, String>,
+    /// キャッシュを保存するディレクトリ。`None` ならキャッシュを使わない。
+    #[rkyv(with = rkyv::with::Map<rkyv::with::AsString>)]
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Context {
+    /// このプロセスのカレントディレクトリと環境変数 (UTF-8 でないものは除く)
+    pub fn current() -> Context {
+        Context {
+            cwd: env::current_dir().ok(),
+            vars: env::vars_os()
+                .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+                .collect(),
+            cache_dir: dirs::cache_dir().map(|dir| dir.join("zsh-prompts")),
+        }
+    }
+
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// `$HOME`。設定されていなければこのプロセスのホームディレクトリ。
+    pub fn home_dir(&self) -> Option<PathBuf> {
+        self.var("HOME")
+            .filter(|home| !home.is_empty())
+            .map(PathBuf::from)
+            .or_else(dirs::home_dir)
+    }
+
+    /// 相対パスをカレントディレクトリからのパスにする
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => cwd.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+}
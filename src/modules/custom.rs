@@ -0,0 +1,361 @@
+use crate::modules::{Color, Context, Icon, PromptSegment, sanitize_untrusted};
+use crate::template::Template;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// `--format` の書式で使えるフィールド
+pub const FIELDS: &[&str] = &["output"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{output}";
+/// `--timeout` を指定しないときにコマンドを待つ時間
+pub const DEFAULT_TIMEOUT_MS: u64 = 500;
+/// 出力にはアイコンを付けないので、評価が間に合わなかったときだけ使う
+pub const CUSTOM_ICON: Icon = Icon::new("\u{f0ad}", "⚙", "*");
+
+/// コマンドの出力かファイルの内容の最初の行を表示する。
+/// 出力が空のときや、コマンドが時間内に終わらなかったときは何も表示しない。
+pub fn get_custom_output(
+    command: &Option<String>,
+    file: &Option<PathBuf>,
+    context: &Context,
+    timeout: Option<u64>,
+    cache_ttl: Option<u64>,
+    color: Option<Color>,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
+    let output = match (command, file) {
+        (Some(command), _) => {
+            let timeout = match timeout.unwrap_or(DEFAULT_TIMEOUT_MS) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            };
+            match cache_ttl.filter(|&ttl| ttl > 0) {
+                Some(ttl) => cached_output(command, context, Duration::from_secs(ttl), timeout),
+                None => run(command, context, timeout),
+            }
+        }
+        (None, Some(file)) => fs::read(context.resolve(file))
+            .ok()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()),
+        (None, None) => None,
+    };
+    let Some(line) = output.as_deref().and_then(first_line) else {
+        return Vec::new();
+    };
+
+    // 色を指定しなければ端末の既定の色で表示する
+    let field = |content: String| PromptSegment {
+        color: color.clone(),
+        ..PromptSegment::new(content)
+    };
+    // コマンドやファイルの内容は外部入力なので無害化する
+    let fields = [("output", field(sanitize_untrusted(line)))];
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &field(String::new()))
+}
+
+// 前後の空白を除いた最初の空でない行
+fn first_line(output: &str) -> Option<&str> {
+    output.lines().map(str::trim).find(|line| !line.is_empty())
+}
+
+/// `sh -c` でコマンドを実行し、標準出力を返す。
+/// 時間内に終わらなければ、`sh` が起動した子プロセスも含めて終了させて `None` を返す。
+fn run(command: &str, context: &Context, timeout: Option<Duration>) -> Option<String> {
+    let mut process = Command::new("sh");
+    process
+        .arg("-c")
+        .arg(command)
+        .env_clear()
+        .envs(&context.vars);
+    if let Some(cwd) = &context.cwd {
+        process.current_dir(cwd);
+    }
+    // 子プロセスごと終了させられるよう、新しいプロセスグループで起動する
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        process.process_group(0);
+    }
+    let mut child = process
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| eprintln!("Error: {}: {}", command, e))
+        .ok()?;
+    // パイプが詰まらないよう、標準出力は別スレッドで読み切る
+    let mut stdout = child.stdout.take()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // UTF-8 でないバイトがあっても出力全体を捨てずに、置換文字にして表示する
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        let _ = tx.send(String::from_utf8_lossy(&output).into_owned());
+    });
+    let output = match timeout {
+        Some(timeout) => rx.recv_timeout(timeout).ok(),
+        None => rx.recv().ok(),
+    };
+    if output.is_none() {
+        kill(&mut child);
+    }
+    let _ = child.wait();
+    output
+}
+
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // プロセスグループのIDは `sh` のPIDと同じ
+        // SAFETY: killpg はシグナルを送るだけで、メモリには触れない
+        unsafe {
+            libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child.kill();
+    }
+}
+
+/// 前回の出力が `ttl` 以内に保存されたものならコマンドを実行せずに使う。
+/// 空の出力もキャッシュするので、何も表示しない状態も `ttl` の間は保たれる。
+fn cached_output(
+    command: &str,
+    context: &Context,
+    ttl: Duration,
+    timeout: Option<Duration>,
+) -> Option<String> {
+    let Some(path) = cache_path(command, context) else {
+        return run(command, context, timeout);
+    };
+    if let Some(output) = load(&path, ttl) {
+        return Some(output);
+    }
+    let output = run(command, context, timeout)?;
+    // キャッシュを保存できなくても表示には影響しない
+    let _ = store(&path, &output);
+    Some(output)
+}
+
+fn store(path: &Path, output: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // 他のシェルが書きかけのファイルを読まないよう、一時ファイルに書いてから置き換える
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_path, output)?;
+    fs::rename(&tmp_path, path)
+}
+
+// 同じコマンドでもディレクトリによって出力が変わるので、カレントディレクトリも鍵に含める。
+// 環境変数は含めない。
+fn cache_path(command: &str, context: &Context) -> Option<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    command.hash(&mut hasher);
+    context.cwd.hash(&mut hasher);
+    Some(
+        context
+            .cache_dir
+            .as_ref()?
+            .join("custom")
+            .join(format!("{:016x}", hasher.finish())),
+    )
+}
+
+fn load(path: &Path, ttl: Duration) -> Option<String> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let age = SystemTime::now().duration_since(modified).ok()?;
+    if age > ttl {
+        return None;
+    }
+    fs::read_to_string(path).ok()
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_custom_output(
+    command: &Option<String>,
+    file: &Option<PathBuf>,
+    context: &Context,
+) -> Vec<(&'static str, String)> {
+    match (command, file) {
+        (Some(command), _) => {
+            let cache = match cache_path(command, context) {
+                Some(path) => match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                    Ok(modified) => format!(
+                        "{} ({}s old)",
+                        path.display(),
+                        SystemTime::now()
+                            .duration_since(modified)
+                            .unwrap_or_default()
+                            .as_secs()
+                    ),
+                    Err(_) => format!("{} (not saved)", path.display()),
+                },
+                None => "unavailable".to_string(),
+            };
+            vec![("command", command.clone()), ("cache", cache)]
+        }
+        (None, Some(file)) => vec![("file", context.resolve(file).display().to_string())],
+        (None, None) => vec![("source", "none (set `command` or `file`)".to_string())],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(segments: &[PromptSegment]) -> String {
+        segments.iter().map(|s| s.content.as_str()).collect()
+    }
+
+    #[test]
+    fn test_command_output_is_shown_only_when_not_empty() {
+        let segments = get_custom_output(
+            &Some("printf '\\n  prod  \\nsecond\\n'".to_string()),
+            &None,
+            &Context::current(),
+            None,
+            None,
+            Some(Color::Red),
+            Some("k8s:{output}"),
+        );
+        assert_eq!(contents(&segments), "k8s:prod");
+        assert!(segments.iter().all(|s| s.color == Some(Color::Red)));
+
+        let command = Some("true".to_string());
+        assert!(
+            get_custom_output(
+                &command,
+                &None,
+                &Context::current(),
+                None,
+                None,
+                None,
+                Some("x{output}")
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_invalid_utf8_output_is_kept() {
+        let segments = get_custom_output(
+            &Some("printf 'caf\\351 ok\\n'".to_string()),
+            &None,
+            &Context::current(),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contents(&segments), "caf\u{fffd} ok");
+    }
+
+    #[test]
+    fn test_slow_command_is_killed_after_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        // `sh` が起動した `sleep` のPIDを書き出す
+        let command = Some(format!(
+            "sleep 5 & echo $! > {}; wait; echo late",
+            pid_file.display()
+        ));
+        let started = std::time::Instant::now();
+        assert!(
+            get_custom_output(
+                &command,
+                &None,
+                &Context::current(),
+                Some(200),
+                None,
+                None,
+                None
+            )
+            .is_empty()
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // `sh` だけでなく `sleep` も終了している (ゾンビは親に回収されるまで残る)
+        #[cfg(target_os = "linux")]
+        {
+            let pid = fs::read_to_string(&pid_file).unwrap();
+            let stat = Path::new("/proc").join(pid.trim()).join("stat");
+            let is_running = || {
+                fs::read_to_string(&stat).is_ok_and(|stat| {
+                    stat.rsplit_once(") ")
+                        .is_some_and(|(_, rest)| !rest.starts_with('Z'))
+                })
+            };
+            let deadline = std::time::Instant::now() + Duration::from_secs(1);
+            while is_running() && std::time::Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!is_running());
+        }
+    }
+
+    #[test]
+    fn test_file_source_and_cache_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("status");
+        fs::write(&file, "on-call\n").unwrap();
+        let segments = get_custom_output(
+            &None,
+            &Some(file.clone()),
+            &Context::default(),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contents(&segments), "on-call");
+
+        // 保存した出力は有効期限内だけ使われる
+        let cache = dir.path().join("cache");
+        fs::write(&cache, "cached\n").unwrap();
+        assert_eq!(
+            load(&cache, Duration::from_secs(60)).as_deref(),
+            Some("cached\n")
+        );
+        assert_eq!(load(&cache, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_cached_output_is_keyed_on_command_and_directory() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let counter = cache_dir.path().join("runs");
+        // 実行されるたびに行が増える
+        let command = format!("echo run >> {0}; wc -l < {0}", counter.display());
+        let ttl = Duration::from_secs(60);
+        let output = |cwd: &Path| {
+            let context = Context {
+                cwd: Some(cwd.to_path_buf()),
+                cache_dir: Some(cache_dir.path().to_path_buf()),
+                ..Context::current()
+            };
+            cached_output(&command, &context, ttl, None).map(|output| output.trim().to_string())
+        };
+
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        assert_eq!(output(first.path()).as_deref(), Some("1"));
+        // 有効期限内は保存した出力を使い、コマンドは実行しない
+        assert_eq!(output(first.path()).as_deref(), Some("1"));
+        // 別のディレクトリでは改めて実行する
+        assert_eq!(output(second.path()).as_deref(), Some("2"));
+        assert_eq!(output(first.path()).as_deref(), Some("1"));
+        // 一時ファイルは置き換えられて残らない
+        let files = fs::read_dir(cache_dir.path().join("custom"))
+            .unwrap()
+            .count();
+        assert_eq!(files, 2);
+    }
+}
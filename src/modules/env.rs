@@ -0,0 +1,289 @@
+use crate::modules::{Color, Context, Icon, IconSet, PromptSegment, sanitize_untrusted};
+use crate::template::Template;
+use regex::Regex;
+use rkyv::rancor::{Fallible, Source};
+use rkyv::string::{ArchivedString, StringResolver};
+use rkyv::with::{ArchiveWith, DeserializeWith, SerializeWith};
+use rkyv::{Place, SerializeUnsized};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// `--format` の書式で使えるフィールド (変数ごとに展開される)
+pub const FIELDS: &[&str] = &["icon", "name", "value"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{?icon:{icon} }{value}";
+
+// アイコンの定義 (Nerd Font / Unicode / ASCII)
+pub const VARIABLE_ICON: Icon = Icon::new("\u{ea88}", "$", "");
+const AWS_ICON: Icon = Icon::new("\u{e7ad}", "☁", "aws");
+const PYTHON_ICON: Icon = Icon::new("\u{e73c}", "py", "py");
+const NIX_ICON: Icon = Icon::new("\u{f313}", "❄", "nix");
+const KUBERNETES_ICON: Icon = Icon::new("\u{f10fe}", "⎈", "k8s");
+
+// よく使われる変数のアイコン。それ以外の変数は `VARIABLE_ICON` になる。
+const KNOWN_VARIABLES: &[(&str, Icon)] = &[
+    ("AWS_PROFILE", AWS_ICON),
+    ("AWS_VAULT", AWS_ICON),
+    ("VIRTUAL_ENV", PYTHON_ICON),
+    ("CONDA_DEFAULT_ENV", PYTHON_ICON),
+    ("IN_NIX_SHELL", NIX_ICON),
+    ("KUBECONFIG", KUBERNETES_ICON),
+];
+
+/// `--value-color` の1項目。値全体に一致する正規表現と、一致したときの色。
+/// 設定ファイルやCLIを読み込むときに1度だけ解釈し、正規表現もそのときにコンパイルする。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ValueColor {
+    /// 書かれたままのパターン (`^(?:...)$` で囲む前)
+    pub pattern: String,
+    pub regex: Regex,
+    pub color: Color,
+}
+
+/// `PATTERN=COLOR` を解釈する。`PATTERN` は値全体に一致する正規表現。
+impl FromStr for ValueColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, color) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected PATTERN=COLOR: {}", s))?;
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())?;
+        let color = color.parse::<Color>().map_err(|e| e.to_string())?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex,
+            color,
+        })
+    }
+}
+
+impl TryFrom<String> for ValueColor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ValueColor> for String {
+    fn from(value_color: ValueColor) -> Self {
+        value_color.to_string()
+    }
+}
+
+impl fmt::Display for ValueColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.color)
+    }
+}
+
+/// `ValueColor` を `PATTERN=COLOR` の文字列としてアーカイブする。
+/// 正規表現はアーカイブできないので、復元するときにコンパイルし直す。
+pub struct AsValueColorString;
+
+impl ArchiveWith<ValueColor> for AsValueColorString {
+    type Archived = ArchivedString;
+    type Resolver = StringResolver;
+
+    fn resolve_with(field: &ValueColor, resolver: Self::Resolver, out: Place<Self::Archived>) {
+        ArchivedString::resolve_from_str(&field.to_string(), resolver, out);
+    }
+}
+
+impl<S> SerializeWith<ValueColor, S> for AsValueColorString
+where
+    S: Fallible + ?Sized,
+    S::Error: Source,
+    str: SerializeUnsized<S>,
+{
+    fn serialize_with(field: &ValueColor, serializer: &mut S) -> Result<Self::Resolver, S::Error> {
+        ArchivedString::serialize_from_str(&field.to_string(), serializer)
+    }
+}
+
+impl<D> DeserializeWith<ArchivedString, ValueColor, D> for AsValueColorString
+where
+    D: Fallible + ?Sized,
+    D::Error: Source,
+{
+    fn deserialize_with(field: &ArchivedString, _: &mut D) -> Result<ValueColor, D::Error> {
+        field
+            .as_str()
+            .parse()
+            .map_err(|e: String| D::Error::new(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+/// 環境変数の値を表示する。設定されていないか空の変数は表示しない。
+/// 値が `value_colors` のいずれかに一致すれば、最初に一致したものの色で表示する。
+pub fn get_env_vars(
+    vars: &[String],
+    context: &Context,
+    color: Option<Color>,
+    value_colors: &[ValueColor],
+    icon: &Option<String>,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
+    let template = Template::parse_or_default(format, DEFAULT_FORMAT);
+
+    let mut segments = Vec::new();
+    for name in vars {
+        let Some(value) = context.var(name).filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        let color = value_colors
+            .iter()
+            .find(|value_color| value_color.regex.is_match(value))
+            .map(|value_color| &value_color.color)
+            .or(color.as_ref())
+            .unwrap_or(&Color::Yellow)
+            .to_string();
+        let field = |content: String| PromptSegment::new_with_color(content, &color);
+        let icon = match icon {
+            Some(icon) => icon.clone(),
+            None => icon_for(name).get(icons).to_string(),
+        };
+        // 値は外部入力なので無害化する
+        // パスの値 (`VIRTUAL_ENV` など) は幅が足りなければ末尾の名前だけを表示する
+        let mut value_field = field(sanitize_untrusted(value));
+        if let Some((_, last)) = value.trim_end_matches('/').rsplit_once('/')
+            && !last.is_empty()
+        {
+            value_field = value_field.with_short(sanitize_untrusted(last));
+        }
+        let fields = [
+            ("icon", field(sanitize_untrusted(&icon)).with_priority(40)),
+            ("name", field(name.clone())),
+            ("value", value_field),
+        ];
+        // 変数ごとに書式を展開し、変数どうしの間には空白を入れる
+        segments.extend(template.expand(&fields, &field(String::new()).with_priority(40)));
+    }
+    segments
+}
+
+fn icon_for(name: &str) -> Icon {
+    KNOWN_VARIABLES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map_or(VARIABLE_ICON, |(_, icon)| *icon)
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_env_vars(
+    vars: &[String],
+    value_colors: &[ValueColor],
+    context: &Context,
+) -> Vec<(&'static str, String)> {
+    let mut explanation: Vec<(&'static str, String)> = vars
+        .iter()
+        .map(|name| {
+            let value = match context.var(name) {
+                Some("") => format!("${} is empty (hidden)", name),
+                Some(value) => {
+                    let matched = value_colors
+                        .iter()
+                        .find(|value_color| value_color.regex.is_match(value));
+                    match matched {
+                        Some(value_color) => {
+                            format!("${} = {:?} (matches {})", name, value, value_color)
+                        }
+                        None => format!("${} = {:?}", name, value),
+                    }
+                }
+                None => format!("${} is not set (hidden)", name),
+            };
+            ("variable", value)
+        })
+        .collect();
+    if explanation.is_empty() {
+        explanation.push(("variable", "none (set `var`)".to_string()));
+    }
+    explanation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(vars: &[(&str, &str)]) -> Context {
+        Context {
+            vars: vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Context::default()
+        }
+    }
+
+    #[test]
+    fn test_value_colors_and_unset_variables() {
+        let vars = [
+            "TEST_ENV_UNSET".to_string(),
+            "TEST_ENV_STAGE".to_string(),
+            "TEST_ENV_EMPTY".to_string(),
+        ];
+        let value_colors: Vec<ValueColor> = ["prod|production=red", "pro=blue"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+
+        let prod = context(&[("TEST_ENV_STAGE", "prod"), ("TEST_ENV_EMPTY", "")]);
+        let segments = get_env_vars(
+            &vars,
+            &prod,
+            None,
+            &value_colors,
+            &None,
+            IconSet::Ascii,
+            None,
+        );
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].content, "prod");
+        assert_eq!(segments[0].color, Some(Color::Red));
+
+        // 値全体に一致しなければ `--color` の色になる
+        let preprod = context(&[("TEST_ENV_STAGE", "preprod")]);
+        let segments = get_env_vars(
+            &vars,
+            &preprod,
+            Some(Color::Green),
+            &value_colors,
+            &Some("@".to_string()),
+            IconSet::Nerd,
+            Some("{icon}{name}={value}"),
+        );
+        let content: String = segments.iter().map(|s| s.content.as_str()).collect();
+        assert_eq!(content, "@TEST_ENV_STAGE=preprod");
+        assert!(segments.iter().all(|s| s.color == Some(Color::Green)));
+
+        assert!(get_env_vars(&vars[..1], &prod, None, &[], &None, IconSet::Nerd, None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_value_color() {
+        let parse = |s: &str| s.parse::<ValueColor>();
+        assert!(parse("a=b=red").unwrap().regex.is_match("a=b"));
+        assert_eq!(parse("a=b=red").unwrap().to_string(), "a=b=red");
+        assert!(parse("prod").is_err());
+        assert!(parse("(=red").is_err());
+        assert!(parse("prod=reed").is_err());
+    }
+
+    #[test]
+    fn test_value_color_is_archived_as_its_source() {
+        let value_color: ValueColor = "prod|stg=#ff0000".parse().unwrap();
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&Archived(vec![value_color])).unwrap();
+        let decoded = rkyv::from_bytes::<Archived, rkyv::rancor::Error>(&bytes).unwrap();
+        assert_eq!(decoded.0[0].to_string(), "prod|stg=#ff0000");
+        assert!(decoded.0[0].regex.is_match("stg"));
+    }
+
+    #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+    struct Archived(#[rkyv(with = rkyv::with::Map<AsValueColorString>)] Vec<ValueColor>);
+}
@@ -1,8 +1,14 @@
-use std::path::PathBuf;
+mod cache;
 
-use crate::modules::{Color, PromptSegment};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::modules::{Color, Context, Icon, IconSet, PromptSegment, Style};
+use crate::profile;
+use crate::template::Template;
+use cache::CacheUse;
 use clap::Args;
-use git2::{Repository, Status, StatusOptions};
+use git2::{ErrorCode, Repository, Status, StatusOptions};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -11,8 +17,50 @@ lazy_static! {
     static ref RE_GITHUB: Regex = Regex::new(r"github\.com").unwrap();
     static ref RE_GITLAB: Regex = Regex::new(r"gitlab\.com").unwrap();
 }
+/// `--format` の書式で使えるフィールド
+pub const FIELDS: &[&str] = &[
+    "icon",
+    "branch_icon",
+    "branch",
+    "staged",
+    "unstaged",
+    "untracked",
+    "conflicts",
+    "conflict_icon",
+    "stash",
+    "clean",
+    "ahead",
+    "ahead_icon",
+    "behind",
+    "behind_icon",
+];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{icon} {?branch_icon:{branch_icon} }{branch}{?staged: +{staged}}{?unstaged: !{unstaged}}{?untracked: ?{untracked}}{?conflicts: {conflict_icon}{conflicts}}{?stash: {stash}}{?clean: {clean}}{?ahead: {ahead_icon}{ahead}}{?behind: {behind_icon}{behind}}";
+
+// アイコンの定義 (Nerd Font / Unicode / ASCII)
+pub const GIT_ICON: Icon = Icon::new("\u{f02a2}", "±", "git");
+const GITHUB_ICON: Icon = GIT_ICON.with_nerd("\u{eba1}");
+const GITLAB_ICON: Icon = GIT_ICON.with_nerd("\u{f296}");
+const BRANCH_ICON: Icon = Icon::new("\u{e725}", "⎇", "");
+const CONFLICT_ICON: Icon = Icon::new("\u{f071}", "⚠", "=");
+const STASH_ICON: Icon = Icon::new("\u{f01c}", "≡", "$");
+const CLEAN_ICON: Icon = Icon::new("\u{f00c}", "✓", "ok");
+const AHEAD_ICON: Icon = Icon::new("↑", "↑", "^");
+const BEHIND_ICON: Icon = Icon::new("↓", "↓", "v");
+
+/// 幅が足りないときに表示するブランチ名の長さ
+const BRANCH_SHORT_LEN: usize = 16;
+
 #[derive(
-    Debug, Clone, Serialize, Deserialize, Args, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive,
+    Debug,
+    Clone,
+    Default,
+    Serialize,
+    Deserialize,
+    Args,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
 )]
 pub struct GitStatusOptions {
     #[arg(long)]
@@ -37,28 +85,210 @@ pub struct GitStatusOptions {
     pub ahead_color_option: Option<Color>,
     #[arg(long)]
     pub behind_color_option: Option<Color>,
+    #[arg(long)]
+    pub git_icon_style_option: Option<Style>,
+    #[arg(long)]
+    pub branch_style_option: Option<Style>,
+    #[arg(long)]
+    pub staged_style_option: Option<Style>,
+    #[arg(long)]
+    pub unstaged_style_option: Option<Style>,
+    #[arg(long)]
+    pub untracked_style_option: Option<Style>,
+    #[arg(long)]
+    pub conflict_style_option: Option<Style>,
+    #[arg(long)]
+    pub stashed_style_option: Option<Style>,
+    #[arg(long)]
+    pub clean_style_option: Option<Style>,
+    #[arg(long)]
+    pub ahead_style_option: Option<Style>,
+    #[arg(long)]
+    pub behind_style_option: Option<Style>,
+}
+
+impl GitStatusOptions {
+    /// 未指定の項目を `base` の値で補う。
+    pub fn merged_with(self, base: &GitStatusOptions) -> GitStatusOptions {
+        GitStatusOptions {
+            default_color_option: self
+                .default_color_option
+                .or_else(|| base.default_color_option.clone()),
+            git_icon_color_option: self
+                .git_icon_color_option
+                .or_else(|| base.git_icon_color_option.clone()),
+            branch_color_option: self
+                .branch_color_option
+                .or_else(|| base.branch_color_option.clone()),
+            staged_color_option: self
+                .staged_color_option
+                .or_else(|| base.staged_color_option.clone()),
+            unstaged_color_option: self
+                .unstaged_color_option
+                .or_else(|| base.unstaged_color_option.clone()),
+            untracked_color_option: self
+                .untracked_color_option
+                .or_else(|| base.untracked_color_option.clone()),
+            conflict_color_option: self
+                .conflict_color_option
+                .or_else(|| base.conflict_color_option.clone()),
+            stashed_color_option: self
+                .stashed_color_option
+                .or_else(|| base.stashed_color_option.clone()),
+            clean_color_option: self
+                .clean_color_option
+                .or_else(|| base.clean_color_option.clone()),
+            ahead_color_option: self
+                .ahead_color_option
+                .or_else(|| base.ahead_color_option.clone()),
+            behind_color_option: self
+                .behind_color_option
+                .or_else(|| base.behind_color_option.clone()),
+            git_icon_style_option: self.git_icon_style_option.or(base.git_icon_style_option),
+            branch_style_option: self.branch_style_option.or(base.branch_style_option),
+            staged_style_option: self.staged_style_option.or(base.staged_style_option),
+            unstaged_style_option: self.unstaged_style_option.or(base.unstaged_style_option),
+            untracked_style_option: self.untracked_style_option.or(base.untracked_style_option),
+            conflict_style_option: self.conflict_style_option.or(base.conflict_style_option),
+            stashed_style_option: self.stashed_style_option.or(base.stashed_style_option),
+            clean_style_option: self.clean_style_option.or(base.clean_style_option),
+            ahead_style_option: self.ahead_style_option.or(base.ahead_style_option),
+            behind_style_option: self.behind_style_option.or(base.behind_style_option),
+        }
+    }
+
+    /// 色の項目と、設定ファイルでのキー
+    pub fn colors_mut(&mut self) -> [(&'static str, &mut Option<Color>); 11] {
+        [
+            ("default_color_option", &mut self.default_color_option),
+            ("git_icon_color_option", &mut self.git_icon_color_option),
+            ("branch_color_option", &mut self.branch_color_option),
+            ("staged_color_option", &mut self.staged_color_option),
+            ("unstaged_color_option", &mut self.unstaged_color_option),
+            ("untracked_color_option", &mut self.untracked_color_option),
+            ("conflict_color_option", &mut self.conflict_color_option),
+            ("stashed_color_option", &mut self.stashed_color_option),
+            ("clean_color_option", &mut self.clean_color_option),
+            ("ahead_color_option", &mut self.ahead_color_option),
+            ("behind_color_option", &mut self.behind_color_option),
+        ]
+    }
+}
+
+/// 作業ツリーのステータスを種類ごとに数えたもの
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive,
+)]
+pub struct StatusCounts {
+    pub staged: usize,
+    pub unstaged: usize,
+    pub untracked: usize,
+    pub conflicts: usize,
 }
 
-pub fn get_git_status(options: GitStatusOptions, path: &Option<PathBuf>) -> Vec<PromptSegment> {
-    let mut segments: Vec<PromptSegment> = Vec::new();
+/// 作業ツリーを走査して見つけたファイルのパスを、件数に数える種類ごとに並べたもの
+#[derive(Debug, Default)]
+struct StatusPaths {
+    staged: Vec<Vec<u8>>,
+    unstaged: Vec<Vec<u8>>,
+    untracked: Vec<Vec<u8>>,
+    conflicted: Vec<Vec<u8>>,
+}
+
+impl StatusPaths {
+    fn counts(&self) -> StatusCounts {
+        StatusCounts {
+            staged: self.staged.len(),
+            unstaged: self.unstaged.len(),
+            untracked: self.untracked.len(),
+            conflicts: self.conflicted.len(),
+        }
+    }
+}
+
+/// `repo.statuses()` で作業ツリー全体を走査して、ファイルを種類ごとに分ける。
+/// 未追跡ファイルのパスはキャッシュのキーに、全てのパスは `explain` の表示に使う。
+fn walk_status(repo: &Repository) -> StatusPaths {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
 
-    let get_color = |specific_color: Color, override_color: Option<Color>| {
+    let mut paths = StatusPaths::default();
+    if let Ok(statuses) = repo.statuses(Some(&mut opts)) {
+        for entry in statuses.iter() {
+            let s = entry.status();
+            let path = entry.path_bytes();
+            if s.is_conflicted() {
+                paths.conflicted.push(path.to_vec());
+            }
+            if s.is_wt_new() {
+                paths.untracked.push(path.to_vec());
+            }
+            if is_unstaged(s) {
+                paths.unstaged.push(path.to_vec());
+            }
+            if is_staged(s) {
+                paths.staged.push(path.to_vec());
+            }
+        }
+    }
+    paths
+}
+
+fn is_staged(s: Status) -> bool {
+    s.intersects(
+        Status::INDEX_NEW
+            | Status::INDEX_MODIFIED
+            | Status::INDEX_DELETED
+            | Status::INDEX_RENAMED
+            | Status::INDEX_TYPECHANGE,
+    )
+}
+
+fn is_unstaged(s: Status) -> bool {
+    s.intersects(
+        Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_RENAMED | Status::WT_TYPECHANGE,
+    )
+}
+
+// HEAD の追跡ブランチと、それに対して進んでいる・遅れているコミット数
+fn upstream(repo: &Repository) -> Option<(String, usize, usize)> {
+    let head = repo.head().ok()?;
+    let local_oid = head.target()?;
+    let upstream_branch = repo.branch_upstream_name(head.name()?).ok()?;
+    let upstream_name = upstream_branch.as_str()?;
+    let upstream_oid = repo.refname_to_id(upstream_name).ok()?;
+    let (ahead, behind) = repo.graph_ahead_behind(local_oid, upstream_oid).ok()?;
+    Some((upstream_name.to_string(), ahead, behind))
+}
+
+pub fn get_git_status(
+    options: &GitStatusOptions,
+    path: &Option<PathBuf>,
+    context: &Context,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
+    let get_color = |specific_color: Color, override_color: &Option<Color>| {
         override_color
-            .or(options.default_color_option)
-            .unwrap_or(specific_color)
+            .as_ref()
+            .or(options.default_color_option.as_ref())
+            .unwrap_or(&specific_color)
             .to_string()
     };
+    let get_style = |specific_style: Style, override_style: Option<Style>| {
+        override_style.unwrap_or(specific_style)
+    };
 
     // 1. カレントディレクトリからリポジトリを探索
-    let mut repo = match if let Some(path) = path {
-        Repository::discover(path)
-    } else {
-        Repository::discover(".")
-    } {
+    let mut repo = match profile::phase("discover", || {
+        Repository::discover(context.resolve(path.as_deref().unwrap_or(Path::new("."))))
+    }) {
         Ok(repo) => repo,
+        // リポジトリの外にいるのは普通のことなので、何も表示しない
+        Err(e) if e.code() == ErrorCode::NotFound => return Vec::new(),
         Err(e) => {
             eprintln!("Error: {}", e);
-            return segments;
+            return Vec::new();
         }
     };
 
@@ -66,19 +296,21 @@ pub fn get_git_status(options: GitStatusOptions, path: &Option<PathBuf>) -> Vec<
     let remote_icon = if let Ok(remote) = repo.find_remote("origin") {
         let url = remote.url().unwrap_or("");
         if RE_GITHUB.is_match(url) {
-            ""
+            GITHUB_ICON
         } else if RE_GITLAB.is_match(url) {
-            ""
+            GITLAB_ICON
         } else {
-            "󰊢"
+            GIT_ICON
         }
     } else {
-        "󰊢"
+        GIT_ICON
     };
-    segments.push(PromptSegment::new_with_color(
-        remote_icon.to_string(),
-        &get_color(Color::Blue, options.git_icon_color_option),
-    ));
+    let icon = PromptSegment::new_with_color(
+        remote_icon.get(icons).to_string(),
+        &get_color(Color::Blue, &options.git_icon_color_option),
+    )
+    .with_style(get_style(Style::NONE, options.git_icon_style_option))
+    .with_priority(40);
 
     // --- Branch / Detached HEAD の取得 ---
     let branch_display;
@@ -103,128 +335,449 @@ pub fn get_git_status(options: GitStatusOptions, path: &Option<PathBuf>) -> Vec<
         branch_display = "empty".to_string();
     }
 
-    segments.push(PromptSegment::new_with_color(
-        "".to_string(),
-        &get_color(Color::White, options.git_icon_color_option),
-    ));
-    segments.push(PromptSegment::new_with_color(
-        branch_display,
-        &get_color(
-            if is_detached {
-                Color::Red
-            } else {
-                Color::Yellow
-            },
-            options.branch_color_option,
-        ),
-    ));
+    let branch_icon = PromptSegment::new_with_color(
+        BRANCH_ICON.get(icons).to_string(),
+        &get_color(Color::White, &options.git_icon_color_option),
+    )
+    .with_style(get_style(Style::NONE, options.git_icon_style_option))
+    .with_priority(40);
+    // ブランチ名はリポジトリ由来の外部入力なので無害化する
+    // Detached HEAD は目立つように太字の赤で表示する
+    let (branch_color, branch_style) = if is_detached {
+        (Color::Red, Style::BOLD)
+    } else {
+        (Color::Yellow, Style::NONE)
+    };
+    let branch = PromptSegment::new_untrusted_with_color(
+        &branch_display,
+        &get_color(branch_color, &options.branch_color_option),
+    )
+    .with_style(get_style(branch_style, options.branch_style_option));
+    // 長いブランチ名は幅が足りなければ先頭だけを表示する
+    let short_branch: String = branch.content.chars().take(BRANCH_SHORT_LEN).collect();
+    let branch = if short_branch.len() < branch.content.len() {
+        branch.with_short(short_branch + "…")
+    } else {
+        branch
+    };
 
     // --- ステータス解析 (Staged, Unstaged, etc.) ---
-    let mut opts = StatusOptions::new();
-    opts.include_untracked(true).recurse_untracked_dirs(true);
-
-    let mut staged = 0;
-    let mut unstaged = 0;
-    let mut untracked = 0;
-    let mut conflicts = 0;
-
-    if let Ok(statuses) = repo.statuses(Some(&mut opts)) {
-        for entry in statuses.iter() {
-            let s = entry.status();
-            if s.is_conflicted() {
-                conflicts += 1;
-            }
-            if s.is_wt_new() {
-                untracked += 1;
-            }
-            if s.intersects(
-                Status::WT_MODIFIED
-                    | Status::WT_DELETED
-                    | Status::WT_RENAMED
-                    | Status::WT_TYPECHANGE,
-            ) {
-                unstaged += 1;
-            }
-            if s.intersects(
-                Status::INDEX_NEW
-                    | Status::INDEX_MODIFIED
-                    | Status::INDEX_DELETED
-                    | Status::INDEX_RENAMED
-                    | Status::INDEX_TYPECHANGE,
-            ) {
-                staged += 1;
-            }
-        }
-    }
+    // 作業ツリーに変化が無ければキャッシュ済みの集計結果を使う
+    let StatusCounts {
+        staged,
+        unstaged,
+        untracked,
+        conflicts,
+    } = {
+        // キャッシュを使えたかで時間が大きく変わるので、`timings` ではそれも示す
+        let started = Instant::now();
+        let (counts, cache_use) = cache::status_counts(&repo, context.cache_dir.as_deref());
+        profile::record(cache_use.status_phase(), started.elapsed());
+        counts
+    };
 
     // --- Ahead / Behind の取得 ---
-    let (mut ahead, mut behind) = (0, 0);
-    if let Ok(head) = repo.head()
-        && let Some(local_oid) = head.target()
-        && let Ok(upstream_branch) = repo.branch_upstream_name(head.name().unwrap_or(""))
-        && let Ok(upstream_oid) = repo.refname_to_id(upstream_branch.as_str().unwrap_or(""))
-        && let Ok((a, b)) = repo.graph_ahead_behind(local_oid, upstream_oid)
-    {
-        ahead = a;
-        behind = b;
-    }
+    let (ahead, behind) =
+        profile::phase("ahead-behind", || upstream(&repo)).map_or((0, 0), |(_, a, b)| (a, b));
 
     // --- Stash の確認 ---
     let mut has_stash = false;
-    let _ = repo.stash_foreach(|_, _, _| {
-        has_stash = true;
-        false // 1つ見つかれば十分なのでイテレーションを止める
+    profile::phase("stash", || {
+        let _ = repo.stash_foreach(|_, _, _| {
+            has_stash = true;
+            false // 1つ見つかれば十分なのでイテレーションを止める
+        });
     });
 
     // --- セグメントの組み立て ---
-    if staged > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("+{}", staged),
-            &get_color(Color::Green, options.staged_color_option),
-        ));
-    }
-    if unstaged > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("!{}", unstaged),
-            &get_color(Color::Red, options.unstaged_color_option),
-        ));
-    }
-    if untracked > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("?{}", untracked),
-            &get_color(Color::Cyan, options.untracked_color_option),
-        ));
+    // 件数は0のとき書式の条件が偽になり、記号を含めて表示されない
+    let is_clean = staged == 0 && unstaged == 0 && untracked == 0 && conflicts == 0 && !has_stash;
+    let field = |content: String, color: Color, color_option, style: Style, style_option| {
+        PromptSegment::new_with_color(content, &get_color(color, color_option))
+            .with_style(get_style(style, style_option))
+    };
+    let fields = [
+        ("icon", icon),
+        ("branch_icon", branch_icon),
+        ("branch", branch),
+        (
+            "staged",
+            field(
+                staged.to_string(),
+                Color::Green,
+                &options.staged_color_option,
+                Style::NONE,
+                options.staged_style_option,
+            ),
+        ),
+        (
+            "unstaged",
+            field(
+                unstaged.to_string(),
+                Color::Red,
+                &options.unstaged_color_option,
+                Style::NONE,
+                options.unstaged_style_option,
+            ),
+        ),
+        (
+            "untracked",
+            field(
+                untracked.to_string(),
+                Color::Cyan,
+                &options.untracked_color_option,
+                Style::NONE,
+                options.untracked_style_option,
+            ),
+        ),
+        (
+            "conflicts",
+            field(
+                conflicts.to_string(),
+                Color::Magenta,
+                &options.conflict_color_option,
+                Style::NONE,
+                options.conflict_style_option,
+            ),
+        ),
+        (
+            "conflict_icon",
+            field(
+                CONFLICT_ICON.get(icons).to_string(),
+                Color::Magenta,
+                &options.conflict_color_option,
+                Style::NONE,
+                options.conflict_style_option,
+            ),
+        ),
+        (
+            "stash",
+            field(
+                if has_stash { STASH_ICON.get(icons) } else { "" }.to_string(),
+                Color::Blue,
+                &options.stashed_color_option,
+                Style::NONE,
+                options.stashed_style_option,
+            )
+            .with_priority(30),
+        ),
+        (
+            "clean",
+            field(
+                if is_clean { CLEAN_ICON.get(icons) } else { "" }.to_string(),
+                Color::Green,
+                &options.clean_color_option,
+                Style::DIM,
+                options.clean_style_option,
+            )
+            .with_priority(50),
+        ),
+        (
+            "ahead",
+            field(
+                ahead.to_string(),
+                Color::White,
+                &options.ahead_color_option,
+                Style::NONE,
+                options.ahead_style_option,
+            ),
+        ),
+        (
+            "ahead_icon",
+            field(
+                AHEAD_ICON.get(icons).to_string(),
+                Color::White,
+                &options.ahead_color_option,
+                Style::NONE,
+                options.ahead_style_option,
+            ),
+        ),
+        (
+            "behind",
+            field(
+                behind.to_string(),
+                Color::Red,
+                &options.behind_color_option,
+                Style::NONE,
+                options.behind_style_option,
+            ),
+        ),
+        (
+            "behind_icon",
+            field(
+                BEHIND_ICON.get(icons).to_string(),
+                Color::Red,
+                &options.behind_color_option,
+                Style::NONE,
+                options.behind_style_option,
+            ),
+        ),
+    ];
+
+    // 区切りの文字列はアイコンと一緒に削除されるよう、アイコンと同じ優先度にする
+    let base = PromptSegment::default().with_priority(40);
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &base)
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_git_status(
+    path: &Option<PathBuf>,
+    context: &Context,
+) -> Vec<(&'static str, String)> {
+    let mut repo =
+        match Repository::discover(context.resolve(path.as_deref().unwrap_or(Path::new(".")))) {
+            Ok(repo) => repo,
+            Err(e) => return vec![("repository", format!("not found ({})", e.message()))],
+        };
+    let list = |paths: &[Vec<u8>]| {
+        if paths.is_empty() {
+            "none".to_string()
+        } else {
+            let paths: Vec<_> = paths
+                .iter()
+                .map(|path| String::from_utf8_lossy(path))
+                .collect();
+            format!("{} ({})", paths.len(), paths.join(", "))
+        }
+    };
+
+    let mut explanation = vec![("repository", repo.path().display().to_string())];
+    let head = match repo.head() {
+        Ok(head) => format!(
+            "{} ({})",
+            head.name().unwrap_or("unknown"),
+            head.target().map(|oid| oid.to_string()).unwrap_or_default()
+        ),
+        Err(_) => "none (no commits yet)".to_string(),
+    };
+    explanation.push(("head", head));
+    explanation.push((
+        "upstream",
+        match upstream(&repo) {
+            Some((name, ahead, behind)) => format!("{} ({} ahead, {} behind)", name, ahead, behind),
+            None => "none".to_string(),
+        },
+    ));
+
+    // プロンプトと同じくキャッシュを通して集計し、キャッシュが使えたかを示す。
+    // 件数に数えたファイルは作業ツリーを走査し直して種類ごとに並べる。
+    let (counts, cache_use) = cache::status_counts(&repo, context.cache_dir.as_deref());
+    let paths = walk_status(&repo);
+    explanation.push((
+        "cache",
+        if cache_use == CacheUse::Hit && counts != paths.counts() {
+            format!(
+                "{} (stale: the counts differ from the working tree)",
+                cache_use
+            )
+        } else {
+            cache_use.to_string()
+        },
+    ));
+    explanation.push(("staged", list(&paths.staged)));
+    explanation.push(("unstaged", list(&paths.unstaged)));
+    explanation.push(("untracked", list(&paths.untracked)));
+    explanation.push(("conflicted", list(&paths.conflicted)));
+
+    let mut stashes = 0;
+    let _ = repo.stash_foreach(|_, _, _| {
+        stashes += 1;
+        true
+    });
+    explanation.push(("stashes", stashes.to_string()));
+    explanation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::OutputMode;
+    use git2::Signature;
+
+    fn init_repo_with_commit(dir: &std::path::Path) -> Repository {
+        let repo = Repository::init(dir).unwrap();
+        {
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            let tree_id = repo.index().unwrap().write_tree().unwrap();
+            let tree = repo.find_tree(tree_id).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+                .unwrap();
+        }
+        repo
     }
-    if conflicts > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("{}", conflicts),
-            &get_color(Color::Magenta, options.conflict_color_option),
-        ));
+
+    #[test]
+    fn test_malicious_branch_name_is_escaped() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo_with_commit(dir.path());
+        let branch_name = "%F{red}$(touch${IFS}pwned)%}";
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.branch(branch_name, &head, false).unwrap();
+        repo.set_head(&format!("refs/heads/{}", branch_name))
+            .unwrap();
+
+        let segments = get_git_status(
+            &GitStatusOptions::default(),
+            &Some(dir.path().to_path_buf()),
+            &Context::default(),
+            IconSet::Nerd,
+            None,
+        );
+        let branch = segments.iter().find(|s| s.content == branch_name).unwrap();
+
+        let zsh = branch.format_for(OutputMode::Zsh);
+        assert_eq!(
+            zsh,
+            "%{\x1b[33m%}%%F{red}$(touch${IFS}pwned)%%}%{\x1b[39m%}"
+        );
     }
-    if has_stash {
-        segments.push(PromptSegment::new_with_color(
-            "".to_string(),
-            &get_color(Color::Blue, options.stashed_color_option),
-        ));
+
+    #[test]
+    fn test_detached_head_is_bold_and_clean_marker_is_dim() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo_with_commit(dir.path());
+        let oid = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(oid).unwrap();
+
+        let segments = get_git_status(
+            &GitStatusOptions::default(),
+            &Some(dir.path().to_path_buf()),
+            &Context::default(),
+            IconSet::Nerd,
+            None,
+        );
+        let branch = segments
+            .iter()
+            .find(|s| s.content.starts_with(':'))
+            .unwrap();
+        assert_eq!(branch.color, Some(Color::Red));
+        assert_eq!(branch.style, Style::BOLD);
+        let clean = segments.last().unwrap();
+        assert_eq!(clean.style, Style::DIM);
+
+        // オプションで装飾を外せる
+        let options = GitStatusOptions {
+            branch_style_option: Some(Style::NONE),
+            ..Default::default()
+        };
+        let segments = get_git_status(
+            &options,
+            &Some(dir.path().to_path_buf()),
+            &Context::default(),
+            IconSet::Nerd,
+            None,
+        );
+        let branch = segments
+            .iter()
+            .find(|s| s.content.starts_with(':'))
+            .unwrap();
+        assert!(branch.style.is_empty());
     }
-    if staged == 0 && unstaged == 0 && untracked == 0 && conflicts == 0 && !has_stash {
-        segments.push(PromptSegment::new_with_color(
-            "".to_string(),
-            &get_color(Color::Green, options.clean_color_option),
-        ));
+
+    #[test]
+    fn test_format_template_rearranges_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo_with_commit(dir.path());
+        std::fs::write(dir.path().join("new.txt"), "x").unwrap();
+
+        let segments = get_git_status(
+            &GitStatusOptions::default(),
+            &Some(dir.path().to_path_buf()),
+            &Context::default(),
+            IconSet::Nerd,
+            Some("[{branch}]{?untracked: new={untracked}}{?staged: +{staged}}"),
+        );
+        let text: String = segments.iter().map(|s| s.content.as_str()).collect();
+        let branch = segments[1].content.clone();
+        assert_eq!(text, format!("[{}] new=1", branch));
+        assert_eq!(segments.last().unwrap().color, Some(Color::Cyan));
     }
-    if ahead > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("↑{}", ahead),
-            &get_color(Color::White, options.ahead_color_option),
-        ));
+
+    #[test]
+    fn test_icon_sets_replace_nerd_font_glyphs() {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo_with_commit(dir.path());
+        std::fs::write(dir.path().join("new.txt"), "x").unwrap();
+        let text = |icons| -> String {
+            get_git_status(
+                &GitStatusOptions::default(),
+                &Some(dir.path().to_path_buf()),
+                &Context::default(),
+                icons,
+                None,
+            )
+            .iter()
+            .map(|s| s.content.as_str())
+            .collect()
+        };
+
+        let branch = Repository::open(dir.path())
+            .unwrap()
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        assert_eq!(text(IconSet::Ascii), format!("git {} ?1", branch));
+        assert_eq!(text(IconSet::Unicode), format!("± ⎇ {} ?1", branch));
+        // Nerd Font のグリフは私用領域にある
+        let is_private_use = |c: char| ('\u{e000}'..='\u{f8ff}').contains(&c) || c >= '\u{f0000}';
+        assert!(text(IconSet::Nerd).chars().any(is_private_use));
+        assert!(!text(IconSet::Unicode).chars().any(is_private_use));
     }
-    if behind > 0 {
-        segments.push(PromptSegment::new_with_color(
-            format!("↓{}", behind),
-            &get_color(Color::Red, options.behind_color_option),
-        ));
+
+    #[test]
+    fn test_explain_lists_counted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo_with_commit(dir.path());
+        std::fs::write(dir.path().join("staged.txt"), "x").unwrap();
+        std::fs::write(dir.path().join("new.txt"), "x").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
+
+        let explanation = explain_git_status(&Some(dir.path().to_path_buf()), &Context::default());
+        let value = |key| {
+            explanation
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.as_str())
+                .unwrap()
+        };
+        assert_eq!(value("cache"), "disabled");
+        assert_eq!(value("staged"), "1 (staged.txt)");
+        assert_eq!(value("untracked"), "1 (new.txt)");
+        assert_eq!(value("unstaged"), "none");
+        assert_eq!(value("upstream"), "none");
+        assert!(value("head").starts_with("refs/heads/"));
     }
 
-    segments
+    #[test]
+    fn test_status_phase_notes_cache_use() {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo_with_commit(&dir.path().join("repo"));
+        let path = Some(dir.path().join("repo"));
+        let phases = |context: &Context| {
+            let (_, _, phases) = profile::profile(|| {
+                get_git_status(
+                    &GitStatusOptions::default(),
+                    &path,
+                    context,
+                    IconSet::Nerd,
+                    None,
+                )
+            });
+            phases
+                .into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| name.starts_with("status"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(phases(&Context::default()), ["status (no cache)"]);
+        let context = Context {
+            cache_dir: Some(dir.path().join("cache")),
+            ..Context::default()
+        };
+        assert_eq!(phases(&context), ["status (cache miss)"]);
+    }
 }
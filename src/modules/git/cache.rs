@@ -0,0 +1,486 @@
+use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{StatusCounts, walk_status};
+use git2::Repository;
+use rkyv::rancor;
+
+/// キャッシュ形式を変更したら上げる
+const CACHE_VERSION: u32 = 2;
+/// 書き込み直後のファイルは同じタイムスタンプのまま再度変更される可能性があるため、
+/// この時間内に更新されたファイルがある間はキャッシュを保存しない
+const RACY_WINDOW_NS: u64 = 2_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+#[rkyv(compare(PartialEq))]
+struct FileStamp {
+    mtime_ns: u64,
+    ctime_ns: u64,
+    size: u64,
+}
+
+#[derive(Debug, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+struct PathStamp {
+    path: Vec<u8>,
+    stamp: Option<FileStamp>,
+}
+
+/// ステータスの集計結果と、その結果が有効であるための条件
+#[derive(Debug, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+struct CacheEntry {
+    version: u32,
+    /// `.git/index`, `.git/config`, `.git/info/exclude` と `core.excludesFile` の状態
+    git_files: Vec<Option<FileStamp>>,
+    /// `.git/HEAD` の内容と、それが指すコミット
+    head: String,
+    head_oid: String,
+    /// 追跡中・未追跡のファイルと、それらを含むディレクトリの状態
+    paths: Vec<PathStamp>,
+    counts: StatusCounts,
+}
+
+/// 集計結果をキャッシュから得られたかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CacheUse {
+    Hit,
+    Miss,
+    /// キャッシュのディレクトリが無いか、bareリポジトリ
+    Disabled,
+}
+
+impl CacheUse {
+    /// `timings` に表示する、ステータス集計の段階名
+    pub(super) fn status_phase(self) -> &'static str {
+        match self {
+            CacheUse::Hit => "status (cache hit)",
+            CacheUse::Miss => "status (cache miss)",
+            CacheUse::Disabled => "status (no cache)",
+        }
+    }
+}
+
+impl fmt::Display for CacheUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheUse::Hit => "hit",
+            CacheUse::Miss => "miss",
+            CacheUse::Disabled => "disabled",
+        })
+    }
+}
+
+/// 作業ツリーのステータスを集計する。
+/// index・HEAD・作業ツリーのいずれにも変化が無ければ前回の結果を再利用する。
+/// `cache_dir` が `None` ならキャッシュを使わない。
+pub(super) fn status_counts(
+    repo: &Repository,
+    cache_dir: Option<&Path>,
+) -> (StatusCounts, CacheUse) {
+    match cache_dir.and_then(|dir| cache_path(repo, dir)) {
+        Some(path) => status_counts_in(repo, &path),
+        None => (walk_status(repo).counts(), CacheUse::Disabled),
+    }
+}
+
+fn status_counts_in(repo: &Repository, cache_path: &Path) -> (StatusCounts, CacheUse) {
+    if let Some(counts) = load(repo, cache_path) {
+        return (counts, CacheUse::Hit);
+    }
+    let paths = walk_status(repo);
+    let counts = paths.counts();
+    // キャッシュを保存できなくても表示には影響しない
+    let _ = store(repo, cache_path, counts, &paths.untracked);
+    (counts, CacheUse::Miss)
+}
+
+fn cache_path(repo: &Repository, cache_dir: &Path) -> Option<PathBuf> {
+    // bareリポジトリには作業ツリーが無い
+    repo.workdir()?;
+    let mut hasher = DefaultHasher::new();
+    repo.path().hash(&mut hasher);
+    Some(
+        cache_dir
+            .join("git")
+            .join(format!("{:016x}.rkyv", hasher.finish())),
+    )
+}
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let mtime_ns = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos() as u64;
+    #[cfg(unix)]
+    let ctime_ns = {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.ctime() as u64)
+            .saturating_mul(1_000_000_000)
+            .saturating_add(metadata.ctime_nsec() as u64)
+    };
+    #[cfg(not(unix))]
+    let ctime_ns = 0;
+    Some(FileStamp {
+        mtime_ns,
+        ctime_ns,
+        size: metadata.len(),
+    })
+}
+
+fn bytes_to_path(workdir: &Path, bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        workdir.join(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        workdir.join(String::from_utf8_lossy(bytes).as_ref())
+    }
+}
+
+fn git_file_stamps(repo: &Repository) -> Vec<Option<FileStamp>> {
+    let git_dir = repo.path();
+    vec![
+        stamp(&git_dir.join("index")),
+        stamp(&git_dir.join("config")),
+        stamp(&git_dir.join("info").join("exclude")),
+        excludes_file(repo).and_then(|path| stamp(&path)),
+    ]
+}
+
+// 全てのリポジトリに効く無視設定。未設定なら git と同じく `$XDG_CONFIG_HOME/git/ignore` を使う。
+fn excludes_file(repo: &Repository) -> Option<PathBuf> {
+    if let Ok(path) = repo
+        .config()
+        .and_then(|config| config.get_path("core.excludesFile"))
+    {
+        return Some(path);
+    }
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(config_home.join("git").join("ignore"))
+}
+
+fn head_state(repo: &Repository) -> (String, String) {
+    let head = fs::read_to_string(repo.path().join("HEAD")).unwrap_or_default();
+    let head_oid = repo
+        .head()
+        .ok()
+        .and_then(|head| head.target())
+        .map(|oid| oid.to_string())
+        .unwrap_or_default();
+    (head, head_oid)
+}
+
+fn load(repo: &Repository, cache_path: &Path) -> Option<StatusCounts> {
+    let workdir = repo.workdir()?;
+    let bytes = fs::read(cache_path).ok()?;
+    // rkyvのアーカイブはアラインされたバッファ上で検証する必要がある
+    let mut aligned = rkyv::util::AlignedVec::<16>::with_capacity(bytes.len());
+    aligned.extend_from_slice(&bytes);
+    let entry = rkyv::access::<ArchivedCacheEntry, rancor::Error>(&aligned).ok()?;
+
+    if entry.version != CACHE_VERSION {
+        return None;
+    }
+    let git_files = git_file_stamps(repo);
+    if entry.git_files.len() != git_files.len()
+        || entry
+            .git_files
+            .iter()
+            .zip(&git_files)
+            .any(|(a, b)| *a != *b)
+    {
+        return None;
+    }
+    let (head, head_oid) = head_state(repo);
+    if entry.head != head || entry.head_oid != head_oid {
+        return None;
+    }
+    // アーカイブをデシリアライズせずにその場で比較する
+    for path_stamp in entry.paths.iter() {
+        if path_stamp.stamp != stamp(&bytes_to_path(workdir, &path_stamp.path)) {
+            return None;
+        }
+    }
+
+    rkyv::deserialize::<StatusCounts, rancor::Error>(&entry.counts).ok()
+}
+
+fn store(
+    repo: &Repository,
+    cache_path: &Path,
+    counts: StatusCounts,
+    untracked_paths: &[Vec<u8>],
+) -> io::Result<()> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| io::Error::other("bare repository"))?;
+    let index = repo.index().map_err(io::Error::other)?;
+
+    // 追跡中・未追跡のファイルと、その祖先ディレクトリ全てを監視対象にする。
+    // ディレクトリのmtimeはエントリの追加・削除で更新されるため、新しい未追跡ファイルも検出できる。
+    let mut watched: BTreeSet<Vec<u8>> = BTreeSet::new();
+    let files = index
+        .iter()
+        .map(|entry| entry.path)
+        .chain(untracked_paths.iter().cloned());
+    for file in files {
+        let mut end = file.len();
+        while let Some(pos) = file[..end].iter().rposition(|&b| b == b'/') {
+            if !watched.insert(file[..pos].to_vec()) {
+                break;
+            }
+            end = pos;
+        }
+        watched.insert(file);
+    }
+    watched.insert(Vec::new());
+
+    let now_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let is_racy = |stamp: &Option<FileStamp>| {
+        stamp.is_some_and(|s| now_ns.saturating_sub(s.mtime_ns) < RACY_WINDOW_NS)
+    };
+
+    let git_files = git_file_stamps(repo);
+    let paths: Vec<PathStamp> = watched
+        .into_iter()
+        .map(|path| PathStamp {
+            stamp: stamp(&bytes_to_path(workdir, &path)),
+            path,
+        })
+        .collect();
+    if git_files.iter().any(is_racy) || paths.iter().any(|p| is_racy(&p.stamp)) {
+        return Ok(());
+    }
+
+    let (head, head_oid) = head_state(repo);
+    let entry = CacheEntry {
+        version: CACHE_VERSION,
+        git_files,
+        head,
+        head_oid,
+        paths,
+        counts,
+    };
+    let bytes = rkyv::to_bytes::<rancor::Error>(&entry).map_err(io::Error::other)?;
+
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // 書きかけのファイルを読まないよう、一時ファイルに書いてから置き換える
+    let tmp_path = cache_path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_path, &bytes)?;
+    fs::rename(&tmp_path, cache_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::fs::File;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    struct Fixture {
+        dir: TempDir,
+        cache_path: PathBuf,
+        repo: Repository,
+        // タイムスタンプが毎回異なるように、巻き戻す量を少しずつ変える
+        age: u64,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let workdir = dir.path().join("repo");
+            fs::create_dir_all(workdir.join("src")).unwrap();
+            fs::write(workdir.join("README.md"), "hello\n").unwrap();
+            fs::write(workdir.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+            let repo = Repository::init(&workdir).unwrap();
+            {
+                let mut index = repo.index().unwrap();
+                index.add_path(Path::new("README.md")).unwrap();
+                index.add_path(Path::new("src/main.rs")).unwrap();
+                index.write().unwrap();
+                let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+                let sig = Signature::now("test", "test@example.com").unwrap();
+                repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+                    .unwrap();
+            }
+            let cache_path = dir.path().join("cache.rkyv");
+            let mut fixture = Self {
+                dir,
+                cache_path,
+                repo,
+                age: 600,
+            };
+            fixture.backdate();
+            fixture
+        }
+
+        fn workdir(&self) -> PathBuf {
+            self.dir.path().join("repo")
+        }
+
+        // 作業ツリーと .git 以下のタイムスタンプを過去にずらし、racyな状態を避ける
+        fn backdate(&mut self) {
+            self.age -= 10;
+            let time = SystemTime::now() - Duration::from_secs(self.age);
+            fn walk(path: &Path, time: SystemTime) {
+                if path.is_dir() {
+                    for entry in fs::read_dir(path).unwrap() {
+                        walk(&entry.unwrap().path(), time);
+                    }
+                }
+                File::open(path).unwrap().set_modified(time).unwrap();
+            }
+            walk(&self.workdir(), time);
+        }
+
+        fn counts(&self) -> StatusCounts {
+            status_counts_in(&self.repo, &self.cache_path).0
+        }
+
+        fn cached(&self) -> Option<StatusCounts> {
+            load(&self.repo, &self.cache_path)
+        }
+    }
+
+    #[test]
+    fn test_unchanged_repo_hits_cache() {
+        let f = Fixture::new();
+        assert_eq!(f.cached(), None);
+        let counts = f.counts();
+        assert_eq!(counts, StatusCounts::default());
+        assert_eq!(f.cached(), Some(counts));
+        assert_eq!(
+            status_counts_in(&f.repo, &f.cache_path),
+            (counts, CacheUse::Hit)
+        );
+    }
+
+    #[test]
+    fn test_modified_tracked_file_invalidates_cache() {
+        let mut f = Fixture::new();
+        f.counts();
+        fs::write(f.workdir().join("src/main.rs"), "fn main() { }\n").unwrap();
+        f.backdate();
+
+        assert_eq!(f.cached(), None);
+        assert_eq!(f.counts().unstaged, 1);
+        assert_eq!(f.cached().map(|c| c.unstaged), Some(1));
+    }
+
+    #[test]
+    fn test_new_untracked_files_invalidate_cache() {
+        let mut f = Fixture::new();
+        f.counts();
+
+        fs::create_dir_all(f.workdir().join("new/dir")).unwrap();
+        fs::write(f.workdir().join("new/dir/a.txt"), "a").unwrap();
+        f.backdate();
+        assert_eq!(f.cached(), None);
+        assert_eq!(f.counts().untracked, 1);
+
+        // 未追跡ディレクトリの中にファイルが増えた場合も検出する
+        fs::write(f.workdir().join("new/dir/b.txt"), "b").unwrap();
+        f.backdate();
+        assert_eq!(f.cached(), None);
+        assert_eq!(f.counts().untracked, 2);
+    }
+
+    #[test]
+    fn test_staging_invalidates_cache() {
+        let mut f = Fixture::new();
+        fs::write(f.workdir().join("README.md"), "changed\n").unwrap();
+        f.backdate();
+        assert_eq!(f.counts().unstaged, 1);
+
+        let mut index = f.repo.index().unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        index.write().unwrap();
+        f.backdate();
+
+        assert_eq!(f.cached(), None);
+        let counts = f.counts();
+        assert_eq!((counts.staged, counts.unstaged), (1, 0));
+    }
+
+    #[test]
+    fn test_head_change_invalidates_cache() {
+        let mut f = Fixture::new();
+        fs::write(f.workdir().join("README.md"), "changed\n").unwrap();
+        let mut index = f.repo.index().unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        index.write().unwrap();
+        f.backdate();
+        assert_eq!(f.counts().staged, 1);
+
+        // index はそのままで HEAD だけを進める
+        {
+            let tree = f.repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = f.repo.head().unwrap().peel_to_commit().unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            f.repo
+                .commit(Some("HEAD"), &sig, &sig, "update", &tree, &[&parent])
+                .unwrap();
+        }
+        f.backdate();
+
+        assert_eq!(f.cached(), None);
+        assert_eq!(f.counts().staged, 0);
+    }
+
+    #[test]
+    fn test_global_excludes_file_invalidates_cache() {
+        let mut f = Fixture::new();
+        let excludes = f.dir.path().join("ignore");
+        let write_excludes = |content: &str, age: u64| {
+            fs::write(&excludes, content).unwrap();
+            File::open(&excludes)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        };
+        write_excludes("", 600);
+        f.repo
+            .config()
+            .unwrap()
+            .set_str("core.excludesFile", excludes.to_str().unwrap())
+            .unwrap();
+        fs::write(f.workdir().join("build.log"), "log").unwrap();
+        f.backdate();
+        assert_eq!(f.counts().untracked, 1);
+        assert!(f.cached().is_some());
+
+        write_excludes("*.log\n", 500);
+        // libgit2 は無視設定の場所をリポジトリを開いたときに読むので、描画のたびと同じく開き直す
+        f.repo = Repository::open(f.workdir()).unwrap();
+        assert_eq!(f.cached(), None);
+        assert_eq!(f.counts().untracked, 0);
+    }
+
+    #[test]
+    fn test_recently_modified_files_are_not_cached() {
+        let f = Fixture::new();
+        fs::write(f.workdir().join("README.md"), "changed\n").unwrap();
+        assert_eq!(f.counts().unstaged, 1);
+        assert_eq!(f.cached(), None);
+    }
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// アイコンの種類を選ぶための環境変数 (`nerd` / `unicode` / `ascii`)
+pub const ICONS_ENV_VAR: &str = "ZSH_PROMPTS_ICONS";
+
+// モジュールが表示するアイコンの種類を定義するenum
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IconSet {
+    /// Nerd Font glyphs (requires a patched font)
+    #[default]
+    Nerd,
+    /// Symbols available in common fonts
+    Unicode,
+    /// Plain ASCII text
+    Ascii,
+}
+
+impl IconSet {
+    /// 環境変数 `ZSH_PROMPTS_ICONS` の値。未設定や不正な値なら `None`。
+    pub fn from_env() -> Option<IconSet> {
+        let value = env::var(ICONS_ENV_VAR).ok()?;
+        <IconSet as clap::ValueEnum>::from_str(value.trim(), true).ok()
+    }
+}
+
+/// アイコンセットごとの表記を持つアイコン。
+/// ASCII の表記が空のアイコンは飾りなので、書式の条件付きの部分と組み合わせて使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Icon {
+    pub nerd: &'static str,
+    pub unicode: &'static str,
+    pub ascii: &'static str,
+}
+
+impl Icon {
+    pub const fn new(nerd: &'static str, unicode: &'static str, ascii: &'static str) -> Self {
+        Self {
+            nerd,
+            unicode,
+            ascii,
+        }
+    }
+
+    /// Nerd Font のグリフだけを差し替える (ディストリビューションごとのロゴなど)
+    pub const fn with_nerd(self, nerd: &'static str) -> Self {
+        Self { nerd, ..self }
+    }
+
+    pub fn get(&self, set: IconSet) -> &'static str {
+        match set {
+            IconSet::Nerd => self.nerd,
+            IconSet::Unicode => self.unicode,
+            IconSet::Ascii => self.ascii,
+        }
+    }
+}
@@ -1,87 +1,112 @@
-use crate::modules::{Color, PromptSegment};
+use crate::modules::{Color, Icon, IconSet, PromptSegment};
+use crate::template::Template;
 use os_info::Type;
 
-pub fn get_os_icon(color: Option<Color>) -> PromptSegment {
+/// `--format` の書式で使えるフィールド
+pub const FIELDS: &[&str] = &["icon", "name", "version"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{icon} ";
+
+// 共通アイコンの定義 (Nerd Font / Unicode / ASCII)
+const LINUX: Icon = Icon::new("", "◆", "linux");
+const WINDOWS: Icon = Icon::new("", "⊞", "win");
+const APPLE: Icon = Icon::new("", "⌘", "mac");
+const BSD: Icon = Icon::new("", "◆", "bsd");
+pub const OTHER: Icon = Icon::new("", "∞", "os");
+const ARCH: Icon = LINUX.with_nerd("󰣇");
+const GENTOO: Icon = LINUX.with_nerd("");
+const AZURE: Icon = LINUX.with_nerd("");
+const CLOUD: Icon = LINUX.with_nerd("");
+const REDHAT: Icon = LINUX.with_nerd("");
+const FEDORA: Icon = LINUX.with_nerd("");
+const SUSE: Icon = LINUX.with_nerd("");
+
+pub fn get_os_icon(
+    color: Option<Color>,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
     let info = os_info::get();
 
-    // 共通アイコンの定義
-    let windows = "";
-    let linux = "";
-    let arch = "󰣇";
-    let free_bsd = "";
-    let gentoo = "";
-    let apple = "";
-    let azure = "";
-    let cloud = "";
-    let redhat = "";
-    let fedora = "";
-    let suse = "";
-    let infinity = "";
     let icon = match info.os_type() {
-        Type::AIX => linux,
-        Type::AlmaLinux => "",
-        Type::Alpaquita => linux,
-        Type::Alpine => "",
-        Type::ALTLinux => linux,
-        Type::Amazon => "",
-        Type::Android => "",
-        Type::AOSC => "",
-        Type::Arch => arch,
-        Type::Artix => "",
-        Type::Bluefin => linux,
-        Type::CachyOS => arch,
-        Type::CentOS => redhat,
-        Type::Cygwin => windows,
-        Type::Debian => "",
-        Type::DragonFly => free_bsd,
-        Type::Elementary => "",
-        Type::Emscripten => "🗲",
-        Type::EndeavourOS => "",
-        Type::Fedora => fedora,
-        Type::FreeBSD => free_bsd,
-        Type::Garuda => "",
-        Type::Gentoo => gentoo,
-        Type::HardenedBSD => free_bsd,
-        Type::Illumos => "",
-        Type::InstantOS => arch,
-        Type::Ios => apple,
-        Type::Kali => "",
-        Type::Linux => linux,
-        Type::Mabox => arch,
-        Type::Macos => apple,
-        Type::Manjaro => "",
-        Type::Mariner => azure,
-        Type::MidnightBSD => free_bsd,
-        Type::Mint => "󰣭",
-        Type::NetBSD => free_bsd,
-        Type::NixOS => "",
-        Type::Nobara => "",
-        Type::OpenBSD => "",
-        Type::OpenCloudOS => cloud,
-        Type::openEuler => linux,
-        Type::openSUSE => suse,
-        Type::OracleLinux => "",
-        Type::PikaOS => "󱗆",
-        Type::Pop => "",
-        Type::Raspbian => "",
-        Type::Redhat => redhat,
-        Type::RedHatEnterprise => redhat,
-        Type::Redox => "Ⓡ",
-        Type::RockyLinux => "",
-        Type::Solus => "",
-        Type::SUSE => suse,
-        Type::Ubuntu => "",
-        Type::Ultramarine => fedora,
-        Type::Unknown => infinity,
-        Type::Uos => linux,
-        Type::Void => "",
-        Type::Windows => windows,
-        Type::Zorin => "",
-        _ => infinity,
+        Type::AIX => LINUX,
+        Type::AlmaLinux => LINUX.with_nerd(""),
+        Type::Alpaquita => LINUX,
+        Type::Alpine => LINUX.with_nerd(""),
+        Type::ALTLinux => LINUX,
+        Type::Amazon => LINUX.with_nerd(""),
+        Type::Android => LINUX.with_nerd(""),
+        Type::AOSC => LINUX.with_nerd(""),
+        Type::Arch => ARCH,
+        Type::Artix => LINUX.with_nerd(""),
+        Type::Bluefin => LINUX,
+        Type::CachyOS => ARCH,
+        Type::CentOS => REDHAT,
+        Type::Cygwin => WINDOWS,
+        Type::Debian => LINUX.with_nerd(""),
+        Type::DragonFly => BSD,
+        Type::Elementary => LINUX.with_nerd(""),
+        Type::Emscripten => OTHER.with_nerd("🗲"),
+        Type::EndeavourOS => LINUX.with_nerd(""),
+        Type::Fedora => FEDORA,
+        Type::FreeBSD => BSD,
+        Type::Garuda => LINUX.with_nerd(""),
+        Type::Gentoo => GENTOO,
+        Type::HardenedBSD => BSD,
+        Type::Illumos => OTHER.with_nerd(""),
+        Type::InstantOS => ARCH,
+        Type::Ios => APPLE,
+        Type::Kali => LINUX.with_nerd(""),
+        Type::Linux => LINUX,
+        Type::Mabox => ARCH,
+        Type::Macos => APPLE,
+        Type::Manjaro => LINUX.with_nerd(""),
+        Type::Mariner => AZURE,
+        Type::MidnightBSD => BSD,
+        Type::Mint => LINUX.with_nerd("󰣭"),
+        Type::NetBSD => BSD,
+        Type::NixOS => LINUX.with_nerd(""),
+        Type::Nobara => LINUX.with_nerd(""),
+        Type::OpenBSD => BSD.with_nerd(""),
+        Type::OpenCloudOS => CLOUD,
+        Type::openEuler => LINUX,
+        Type::openSUSE => SUSE,
+        Type::OracleLinux => LINUX.with_nerd(""),
+        Type::PikaOS => LINUX.with_nerd("󱗆"),
+        Type::Pop => LINUX.with_nerd(""),
+        Type::Raspbian => LINUX.with_nerd(""),
+        Type::Redhat => REDHAT,
+        Type::RedHatEnterprise => REDHAT,
+        Type::Redox => OTHER.with_nerd("Ⓡ"),
+        Type::RockyLinux => LINUX.with_nerd(""),
+        Type::Solus => LINUX.with_nerd(""),
+        Type::SUSE => SUSE,
+        Type::Ubuntu => LINUX.with_nerd(""),
+        Type::Ultramarine => FEDORA,
+        Type::Unknown => OTHER,
+        Type::Uos => LINUX,
+        Type::Void => LINUX.with_nerd(""),
+        Type::Windows => WINDOWS,
+        Type::Zorin => LINUX.with_nerd(""),
+        _ => OTHER,
     };
 
-    PromptSegment::new_with_color(
-        icon.to_string() + " ",
-        &color.unwrap_or(Color::White).to_string(),
-    )
+    // OSのアイコンは飾りなので、幅が足りなければ最初に削除する
+    let color = color.unwrap_or(Color::White).to_string();
+    let field = |content: String| PromptSegment::new_with_color(content, &color).with_priority(10);
+    let fields = [
+        ("icon", field(icon.get(icons).to_string())),
+        ("name", field(info.os_type().to_string())),
+        ("version", field(info.version().to_string())),
+    ];
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &field(String::new()))
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_os_icon() -> Vec<(&'static str, String)> {
+    let info = os_info::get();
+    vec![
+        ("type", format!("{:?}", info.os_type())),
+        ("version", info.version().to_string()),
+    ]
 }
@@ -1,20 +1,47 @@
-use crate::modules::{Color, PromptSegment};
-use std::env;
+use crate::modules::{Color, Context, Icon, IconSet, PromptSegment, sanitize_untrusted};
+use crate::template::Template;
 use std::fs;
+use std::path::{Path, PathBuf};
 
-pub fn get_smart_pwd(color: Option<Color>) -> Vec<PromptSegment> {
-    let current_dir = match env::current_dir() {
-        Ok(path) => path,
-        Err(_) => {
-            return vec![
-                PromptSegment::new_with_color(" ".to_string(), &Color::Red.to_string()),
-                PromptSegment::new_with_color("Error".to_string(), &Color::Red.to_string()),
-            ];
+/// `--format` の書式で使えるフィールド
+pub const FIELDS: &[&str] = &["icon", "path"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{?icon:{icon} }{path}";
+
+// アイコンの定義 (Nerd Font / Unicode / ASCII)
+pub const FOLDER_ICON: Icon = Icon::new("\u{f07c}", "▸", "");
+const LOCK_ICON: Icon = Icon::new("\u{f023}", "⊘", "[ro]");
+const HOME_ICON: Icon = Icon::new("\u{f015}", "⌂", "");
+
+pub fn get_smart_pwd(
+    context: &Context,
+    color: Option<Color>,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
+    let current_dir = match &context.cwd {
+        Some(path) => path,
+        None => {
+            return [FOLDER_ICON.get(icons), "Error"]
+                .into_iter()
+                .filter(|content| !content.is_empty())
+                .map(|content| {
+                    PromptSegment::new_with_color(content.to_string(), &Color::Red.to_string())
+                })
+                .collect();
         }
     };
 
-    let home_dir = dirs::home_dir();
+    smart_pwd_segments(current_dir, context.home_dir(), color, icons, format)
+}
 
+fn smart_pwd_segments(
+    current_dir: &Path,
+    home_dir: Option<PathBuf>,
+    color: Option<Color>,
+    icons: IconSet,
+    format: Option<&str>,
+) -> Vec<PromptSegment> {
     let mut display_path = current_dir.to_string_lossy().to_string();
 
     if let Some(home) = home_dir {
@@ -25,17 +52,17 @@ pub fn get_smart_pwd(color: Option<Color>) -> Vec<PromptSegment> {
         }
     }
 
-    let mut icon = ""; // Default folder icon
+    let mut icon = FOLDER_ICON; // Default folder icon
 
     // Check write permissions for the current directory
-    if let Ok(metadata) = fs::metadata(&current_dir) {
+    if let Ok(metadata) = fs::metadata(current_dir) {
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt; // Corrected: PermissionsExt trait
             let permissions = metadata.permissions().mode(); // Corrected: use metadata.permissions().mode()
             // Check if owner, group, or others have write permission
             if permissions & 0o222 == 0 {
-                icon = ""; // Lock icon if not writable
+                icon = LOCK_ICON; // Lock icon if not writable
             }
         }
         #[cfg(windows)]
@@ -47,7 +74,7 @@ pub fn get_smart_pwd(color: Option<Color>) -> Vec<PromptSegment> {
     }
 
     if display_path == "~" {
-        icon = ""; // Home icon
+        icon = HOME_ICON; // Home icon
     }
 
     // Basic path truncation: if too long, show start...end
@@ -69,11 +96,100 @@ pub fn get_smart_pwd(color: Option<Color>) -> Vec<PromptSegment> {
         }
     }
 
-    vec![
-        PromptSegment::new_with_color(icon.to_string(), &color.unwrap_or(Color::Cyan).to_string()),
-        PromptSegment::new_with_color(
-            display_path.to_string(),
-            &color.unwrap_or(Color::Cyan).to_string(),
+    let color = color.unwrap_or(Color::Cyan).to_string();
+    // アイコンはパスより先に削除する
+    let icon = PromptSegment::new_with_color(icon.get(icons).to_string(), &color).with_priority(40);
+    let fields = [
+        ("icon", icon.clone()),
+        // ディレクトリ名はファイルシステム由来の外部入力なので無害化する
+        // 幅が足りなければ末尾のディレクトリ名だけを表示する
+        (
+            "path",
+            PromptSegment::new_untrusted_with_color(&display_path, &color)
+                .with_short(sanitize_untrusted(&short_path(&display_path))),
         ),
+    ];
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &icon)
+}
+
+// `~/src/project` を `…/project` のように末尾のディレクトリ名だけにする
+fn short_path(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((parent, last)) if !parent.is_empty() && !last.is_empty() => format!("…/{}", last),
+        _ => path.to_string(),
+    }
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_smart_pwd(context: &Context) -> Vec<(&'static str, String)> {
+    let path = |path: Option<PathBuf>| {
+        path.map(|path| path.display().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    let current_dir = context.cwd.clone();
+    let writable = current_dir
+        .as_ref()
+        .and_then(|dir| fs::metadata(dir).ok())
+        .map(|metadata| (!metadata.permissions().readonly()).to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    vec![
+        ("directory", path(current_dir)),
+        ("home", path(context.home_dir())),
+        ("writable", writable),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::OutputMode;
+
+    #[test]
+    fn test_smart_pwd_sanitizes_malicious_directory_names() {
+        let dir = PathBuf::from("/tmp/%F{red}\x1b[2J\x1b]0;pwned\x07/evil\\dir");
+        let segments = smart_pwd_segments(&dir, None, None, IconSet::Nerd, None);
+
+        assert_eq!(
+            segments[1].content,
+            "/tmp/%F{red}\\x1b[2J\\x1b]0;pwned\\x07/evil\\\\dir"
+        );
+        let zsh = segments[1].format_for(OutputMode::Zsh);
+        assert!(zsh.contains("%%F{red}"));
+        assert_eq!(zsh.matches('\x1b').count(), 2);
+    }
+
+    #[test]
+    fn test_smart_pwd_replaces_home_with_tilde() {
+        let home = PathBuf::from("/home/user");
+        let segments = smart_pwd_segments(
+            &home.join("src"),
+            Some(home.clone()),
+            None,
+            IconSet::Nerd,
+            None,
+        );
+        assert_eq!(segments[1].content, "~/src");
+        assert_eq!(segments[1].short.as_deref(), Some("…/src"));
+
+        let segments = smart_pwd_segments(
+            &home.join("src"),
+            Some(home.clone()),
+            None,
+            IconSet::Nerd,
+            Some("in {path}"),
+        );
+        assert_eq!(segments[0].content, "in ");
+        assert_eq!(segments[1].content, "~/src");
+        assert!(segments[1].glue);
+    }
+
+    #[test]
+    fn test_smart_pwd_ascii_icons_drop_decorative_glyphs() {
+        let home = PathBuf::from("/home/user");
+        let segments =
+            smart_pwd_segments(&home.join("src"), Some(home), None, IconSet::Ascii, None);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].content, "~/src");
+        assert!(!segments[0].glue);
+    }
+}
@@ -1,10 +1,32 @@
-use crate::modules::{Color, PromptSegment};
+use crate::modules::{Color, Icon, IconSet, PromptSegment};
+use crate::template::Template;
 use chrono::Local;
 
-pub fn get_time(color: Option<Color>) -> PromptSegment {
+/// `--format` の書式で使えるフィールド
+pub const FIELDS: &[&str] = &["icon", "time", "date"];
+/// `--format` を指定しないときの書式
+pub const DEFAULT_FORMAT: &str = "{?icon:{icon} }{time}";
+
+pub const CLOCK_ICON: Icon = Icon::new("\u{f017}", "◷", "");
+
+pub fn get_time(color: Option<Color>, icons: IconSet, format: Option<&str>) -> Vec<PromptSegment> {
     let now = Local::now();
-    PromptSegment::new_with_color(
-        format!(" {}", now.format("%H:%M:%S")),
-        &color.unwrap_or(Color::Magenta).to_string(),
-    )
+    let color = color.unwrap_or(Color::Magenta).to_string();
+    let field = |content: String| PromptSegment::new_with_color(content, &color).with_priority(20);
+    // アイコンは時刻より先に削除し、時刻は幅が足りなければ秒を省く
+    let icon = field(CLOCK_ICON.get(icons).to_string()).with_priority(10);
+    let fields = [
+        (
+            "time",
+            field(now.format("%H:%M:%S").to_string()).with_short(now.format("%H:%M").to_string()),
+        ),
+        ("date", field(now.format("%Y-%m-%d").to_string())),
+        ("icon", icon.clone()),
+    ];
+    Template::parse_or_default(format, DEFAULT_FORMAT).expand(&fields, &icon)
+}
+
+/// `explain` で表示する、セグメントの元になった情報
+pub fn explain_time() -> Vec<(&'static str, String)> {
+    vec![("now", Local::now().to_rfc3339())]
 }
@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+thread_local! {
+    // 計測中のスレッドだけが `Some` を持つ。普段の描画では何も記録しない。
+    static PHASES: RefCell<Option<Vec<(&'static str, Duration)>>> = const { RefCell::new(None) };
+}
+
+/// モジュールやその処理段階 (`git` の `status` など) にかかった時間
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Timing {
+    pub module: String,
+    /// モジュール全体なら `total`
+    pub phase: String,
+    pub ms: f64,
+}
+
+/// 処理段階の時間を計る。`profile` の中で呼ばれたときだけ記録される。
+pub fn phase<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(name, started.elapsed());
+    result
+}
+
+/// 計り終えてから名前の決まる処理段階 (キャッシュを使えたかで分ける場合など) の時間を記録する。
+pub fn record(name: &'static str, elapsed: Duration) {
+    PHASES.with(|phases| {
+        if let Some(phases) = phases.borrow_mut().as_mut() {
+            phases.push((name, elapsed));
+        }
+    });
+}
+
+/// `f` を実行し、全体の時間と、その中で `phase` が記録した時間を返す。
+pub fn profile<T>(f: impl FnOnce() -> T) -> (T, Duration, Vec<(&'static str, Duration)>) {
+    let outer = PHASES.with(|phases| phases.borrow_mut().replace(Vec::new()));
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    let phases = PHASES
+        .with(|phases| phases.replace(outer))
+        .unwrap_or_default();
+    (result, elapsed, phases)
+}
+
+/// 計測結果を表にする
+pub fn timings_table(timings: &[Timing]) -> String {
+    let module_width = timings
+        .iter()
+        .map(|timing| timing.module.len())
+        .chain(["module".len()])
+        .max()
+        .unwrap_or_default();
+    let phase_width = timings
+        .iter()
+        .map(|timing| timing.phase.len())
+        .chain(["phase".len()])
+        .max()
+        .unwrap_or_default();
+    let mut out = format!(
+        "{:<module_width$}  {:<phase_width$}  {:>10}\n",
+        "module", "phase", "ms"
+    );
+    for timing in timings {
+        out.push_str(&format!(
+            "{:<module_width$}  {:<phase_width$}  {:>10.3}\n",
+            timing.module, timing.phase, timing.ms
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_are_recorded_only_while_profiling() {
+        phase("ignored", || ());
+        let (value, total, phases) = profile(|| {
+            phase("first", || ());
+            phase("second", || 42)
+        });
+        assert_eq!(value, 42);
+        let names: Vec<&str> = phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert!(phases.iter().all(|(_, elapsed)| *elapsed <= total));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::modules::{Color, ColorDepth, OutputMode, PromptSegment, wrap_invisible_for_zsh};
+
+/// セグメントを出力形式に合わせて整形し、空白区切りで連結する。
+/// JSONの場合はセグメントの配列をそのままシリアライズする。
+///
+/// 背景色が同じ連続したセグメントは1つのブロックとして塗りつぶされ、
+/// ブロックの境界には左側のセグメントの区切り記号が
+/// 「前景色 = 左の背景色、背景色 = 右の背景色」で描かれる。
+/// 色は `depth` で表せる範囲に落としてから出力する。
+pub fn render(segments: &[PromptSegment], mode: OutputMode, depth: ColorDepth) -> String {
+    if mode == OutputMode::Json {
+        return serde_json::to_string(segments).unwrap_or_else(|_| "[]".to_string());
+    }
+
+    // 色を使わない場合は装飾を全て外し、内容だけを空白区切りで出力する
+    let depth = depth.resolve();
+    let plain: Vec<PromptSegment>;
+    let segments = if depth == ColorDepth::NoColor {
+        plain = segments
+            .iter()
+            .map(PromptSegment::without_styling)
+            .collect();
+        &plain
+    } else {
+        segments
+    };
+
+    let mut out = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match i.checked_sub(1).map(|j| &segments[j]) {
+            // 同じブロック内は背景色を保ったまま空白で区切る (書式で連結されたものはそのまま)
+            Some(prev) if prev.background == segment.background && segment.glue => {}
+            Some(prev) if prev.background == segment.background => out.push(' '),
+            Some(prev) => {
+                out.push_str(&transition(prev, segment.background.as_ref(), false, depth))
+            }
+            None => {
+                if let Some(bg) = &segment.background {
+                    out.push_str(&format!("\x1b[{}m ", bg.as_ansi_bg_code(depth)));
+                }
+            }
+        }
+        out.push_str(&segment.format_unwrapped(mode, depth));
+    }
+    if let Some(last) = segments.last() {
+        out.push_str(&transition(last, None, true, depth));
+    }
+
+    match mode {
+        OutputMode::Zsh => wrap_invisible_for_zsh(&out),
+        _ => out,
+    }
+}
+
+// 背景色 `left.background` のブロックを閉じて、背景色 `next` のブロックを開く。
+// `is_end` の場合は行末なので、閉じた後に区切りの空白を入れない。
+fn transition(
+    left: &PromptSegment,
+    next: Option<&Color>,
+    is_end: bool,
+    depth: ColorDepth,
+) -> String {
+    let trailing = if is_end { "" } else { " " };
+    let Some(left_bg) = &left.background else {
+        return match next {
+            Some(next_bg) => format!(" \x1b[{}m ", next_bg.as_ansi_bg_code(depth)),
+            None => trailing.to_string(),
+        };
+    };
+
+    match (left.separator.glyph(left.icons), next) {
+        (Some(glyph), Some(next_bg)) => format!(
+            " \x1b[{};{}m{}\x1b[39m ",
+            left_bg.as_ansi_code(depth),
+            next_bg.as_ansi_bg_code(depth),
+            glyph
+        ),
+        (Some(glyph), None) => format!(
+            " \x1b[49;{}m{}\x1b[39m{}",
+            left_bg.as_ansi_code(depth),
+            glyph,
+            trailing
+        ),
+        (None, Some(next_bg)) => format!(" \x1b[{}m ", next_bg.as_ansi_bg_code(depth)),
+        (None, None) => format!(" \x1b[49m{}", trailing),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{IconSet, Separator, Style};
+
+    fn block(content: &str, bg: Option<Color>, separator: Separator) -> PromptSegment {
+        PromptSegment {
+            content: content.to_string(),
+            background: bg,
+            separator,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render_without_background_joins_with_spaces() {
+        let segments = [
+            PromptSegment::new("a".to_string()),
+            PromptSegment::new_with_color("b".to_string(), "red"),
+        ];
+        assert_eq!(
+            render(&segments, OutputMode::Raw, ColorDepth::TrueColor),
+            "a \x1b[31mb\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn test_render_powerline_transitions() {
+        let segments = [
+            block("a", Some(Color::Blue), Separator::Powerline),
+            block("b", Some(Color::Blue), Separator::Powerline),
+            block("c", Some(Color::Green), Separator::Powerline),
+        ];
+        assert_eq!(
+            render(&segments, OutputMode::Raw, ColorDepth::TrueColor),
+            "\x1b[44m a b \x1b[34;42m\u{e0b0}\x1b[39m c \x1b[49;32m\u{e0b0}\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn test_render_powerline_into_plain_segment() {
+        let segments = [
+            block("a", Some(Color::Rgb(1, 2, 3)), Separator::Round),
+            block("b", None, Separator::Space),
+        ];
+        assert_eq!(
+            render(&segments, OutputMode::Zsh, ColorDepth::TrueColor),
+            "%{\x1b[48;2;1;2;3m%} a %{\x1b[49;38;2;1;2;3m%}\u{e0b4}%{\x1b[39m%} b"
+        );
+    }
+
+    #[test]
+    fn test_render_separator_follows_icon_set() {
+        let mut segments = [
+            block("a", Some(Color::Blue), Separator::Powerline),
+            block("b", Some(Color::Green), Separator::Slant),
+        ];
+        segments[0].icons = IconSet::Ascii;
+        segments[1].icons = IconSet::Unicode;
+        assert_eq!(
+            render(&segments, OutputMode::Raw, ColorDepth::TrueColor),
+            "\x1b[44m a \x1b[34;42m>\x1b[39m b \x1b[49;32m◢\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn test_render_downsamples_to_color_depth() {
+        let segments = [
+            block("a", Some(Color::Rgb(0, 0, 0xee)), Separator::Powerline),
+            block("b", None, Separator::Space),
+        ];
+        assert_eq!(
+            render(&segments, OutputMode::Raw, ColorDepth::Ansi16),
+            "\x1b[44m a \x1b[49;34m\u{e0b0}\x1b[39m b"
+        );
+    }
+
+    #[test]
+    fn test_render_without_color_keeps_only_content() {
+        let mut segments = [
+            block("a", Some(Color::Blue), Separator::Powerline),
+            PromptSegment::new_with_color("100%".to_string(), "red").with_style(Style::BOLD),
+        ];
+        segments[0].color = Some(Color::White);
+        assert_eq!(
+            render(&segments, OutputMode::Zsh, ColorDepth::NoColor),
+            "a 100%%"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let mut segment = PromptSegment::new_with_color("main".to_string(), "#ff8000");
+        segment.module = Some("git".to_string());
+        let json = render(&[segment], OutputMode::Json, ColorDepth::TrueColor);
+        assert_eq!(
+            json,
+            r##"[{"content":"main","color":"#ff8000","module":"git","background":null,"separator":"space","style":"none","priority":100}]"##
+        );
+    }
+}
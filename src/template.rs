@@ -0,0 +1,335 @@
+use std::fmt;
+
+use crate::modules::PromptSegment;
+
+/// モジュールの出力を組み立てる書式。
+///
+/// - `{name}` はフィールドの値に置き換わる
+/// - `{?name:...}` はフィールドが空でも `0` でもないときだけ `...` を出力する (入れ子にできる)
+/// - `{{` と `}}` はそれぞれ `{` と `}` を表す (条件付きの部分の中では `}` で必ず終わる)
+///
+/// 例: `"{icon} {branch}{?staged: +{staged}}"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Field(String),
+    Section { field: String, body: Vec<Node> },
+}
+
+/// 書式の誤り。`position` は先頭からの文字数。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid format at character {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, TemplateError> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut pos = 0;
+        let nodes = parse_nodes(&chars, &mut pos, false)?;
+        Ok(Template { nodes })
+    }
+
+    /// モジュールに指定された書式を解釈する。誤りがあれば `default` を使う。
+    /// 誤りは設定を読み込んだときに報告済みなので、描画中は何も出力しない。
+    pub fn parse_or_default(format: Option<&str>, default: &str) -> Template {
+        format
+            .and_then(|format| Template::parse(format).ok())
+            .or_else(|| Template::parse(default).ok())
+            .unwrap_or(Template { nodes: Vec::new() })
+    }
+
+    /// 書式の中で参照されているフィールド名
+    pub fn fields(&self) -> Vec<&str> {
+        fn collect<'a>(nodes: &'a [Node], out: &mut Vec<&'a str>) {
+            for node in nodes {
+                match node {
+                    Node::Text(_) => {}
+                    Node::Field(name) => out.push(name),
+                    Node::Section { field, body } => {
+                        out.push(field);
+                        collect(body, out);
+                    }
+                }
+            }
+        }
+        let mut out = Vec::new();
+        collect(&self.nodes, &mut out);
+        out
+    }
+
+    /// フィールドの値からセグメントを組み立てる。
+    ///
+    /// フィールドの値はそれぞれの色や装飾を持ったセグメントで、内容が置き換わる文字列になる。
+    /// 条件付きの部分に書かれた文字列は条件のフィールドと同じ見た目になり、
+    /// それ以外の文字列は `base` の見た目になる。
+    /// 書式どおりの間隔で表示されるよう、先頭以外のセグメントは空白を挟まずに連結される。
+    pub fn expand(
+        &self,
+        fields: &[(&str, PromptSegment)],
+        base: &PromptSegment,
+    ) -> Vec<PromptSegment> {
+        let mut out: Vec<PromptSegment> = Vec::new();
+        expand_nodes(&self.nodes, fields, base, &mut out);
+        if let Some(first) = out.first_mut() {
+            first.glue = false;
+        }
+        out
+    }
+}
+
+/// clapの引数として書式を受け取る。モジュールの実行時に改めて解釈するため文字列のまま返す。
+pub fn parse_format_arg(s: &str) -> Result<String, TemplateError> {
+    Template::parse(s)?;
+    Ok(s.to_string())
+}
+
+fn parse_nodes(chars: &[char], pos: &mut usize, nested: bool) -> Result<Vec<Node>, TemplateError> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        match c {
+            '{' if chars.get(*pos + 1) == Some(&'{') => {
+                text.push('{');
+                *pos += 2;
+            }
+            '}' if !nested && chars.get(*pos + 1) == Some(&'}') => {
+                text.push('}');
+                *pos += 2;
+            }
+            // 条件付きの部分の終わり
+            '}' if nested => break,
+            '}' => {
+                return Err(TemplateError {
+                    position: *pos,
+                    message: "unmatched `}` (use `}}` for a literal brace)",
+                });
+            }
+            '{' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(parse_placeholder(chars, pos)?);
+            }
+            c => {
+                text.push(c);
+                *pos += 1;
+            }
+        }
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    Ok(nodes)
+}
+
+// `{` の位置から `{name}` または `{?name:...}` を読む
+fn parse_placeholder(chars: &[char], pos: &mut usize) -> Result<Node, TemplateError> {
+    let start = *pos;
+    *pos += 1;
+    let conditional = chars.get(*pos) == Some(&'?');
+    if conditional {
+        *pos += 1;
+    }
+
+    let name_start = *pos;
+    while chars
+        .get(*pos)
+        .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+    {
+        *pos += 1;
+    }
+    let name: String = chars[name_start..*pos].iter().collect();
+    if name.is_empty() {
+        return Err(TemplateError {
+            position: *pos,
+            message: "expected a field name",
+        });
+    }
+
+    match (conditional, chars.get(*pos)) {
+        (false, Some('}')) => {
+            *pos += 1;
+            Ok(Node::Field(name))
+        }
+        (true, Some(':')) => {
+            *pos += 1;
+            let body = parse_nodes(chars, pos, true)?;
+            if chars.get(*pos) != Some(&'}') {
+                return Err(TemplateError {
+                    position: start,
+                    message: "unclosed `{?`",
+                });
+            }
+            *pos += 1;
+            Ok(Node::Section { field: name, body })
+        }
+        (_, None) => Err(TemplateError {
+            position: start,
+            message: "unclosed `{`",
+        }),
+        (false, Some(_)) => Err(TemplateError {
+            position: *pos,
+            message: "expected `}` after the field name",
+        }),
+        (true, Some(_)) => Err(TemplateError {
+            position: *pos,
+            message: "expected `:` after the field name",
+        }),
+    }
+}
+
+// 空文字列と `0` は偽として扱う
+fn is_truthy(fields: &[(&str, PromptSegment)], name: &str) -> bool {
+    fields
+        .iter()
+        .find(|(field, _)| *field == name)
+        .is_some_and(|(_, segment)| !segment.content.is_empty() && segment.content != "0")
+}
+
+fn expand_nodes(
+    nodes: &[Node],
+    fields: &[(&str, PromptSegment)],
+    context: &PromptSegment,
+    out: &mut Vec<PromptSegment>,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => push(
+                out,
+                PromptSegment {
+                    content: text.clone(),
+                    short: None,
+                    ..context.clone()
+                },
+            ),
+            Node::Field(name) => {
+                if let Some((_, segment)) = fields.iter().find(|(field, _)| field == name) {
+                    push(out, segment.clone());
+                }
+            }
+            Node::Section { field, body } => {
+                if is_truthy(fields, field)
+                    && let Some((_, segment)) = fields.iter().find(|(name, _)| name == field)
+                {
+                    expand_nodes(body, fields, segment, out);
+                }
+            }
+        }
+    }
+}
+
+// 見た目が同じなら直前のセグメントにつなげ、違えば空白を挟まない別のセグメントとして追加する
+fn push(out: &mut Vec<PromptSegment>, segment: PromptSegment) {
+    if segment.content.is_empty() {
+        return;
+    }
+    if let Some(last) = out.last_mut()
+        && last.color == segment.color
+        && last.background == segment.background
+        && last.style == segment.style
+        && last.priority == segment.priority
+    {
+        last.content.push_str(&segment.content);
+        // 短い表示は元のセグメント単体のものなので、つなげた後は使えない
+        last.short = None;
+        return;
+    }
+    out.push(PromptSegment {
+        glue: true,
+        ..segment
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{Color, Style};
+
+    fn field(name: &'static str, content: &str, color: &str) -> (&'static str, PromptSegment) {
+        (
+            name,
+            PromptSegment::new_with_color(content.to_string(), color),
+        )
+    }
+
+    #[test]
+    fn test_parse_errors_report_position() {
+        assert_eq!(
+            Template::parse("{icon").unwrap_err(),
+            TemplateError {
+                position: 0,
+                message: "unclosed `{`"
+            }
+        );
+        assert_eq!(Template::parse("a}b").unwrap_err().position, 1);
+        assert_eq!(Template::parse("{?x:{y}").unwrap_err().position, 0);
+        assert_eq!(Template::parse("{?x}").unwrap_err().position, 3);
+        assert_eq!(Template::parse("{}").unwrap_err().position, 1);
+        assert!(Template::parse("{{literal}} {?a:{?b:{c}}}").is_ok());
+        assert_eq!(
+            Template::parse("{icon} {?staged:+{staged}}")
+                .unwrap()
+                .fields(),
+            ["icon", "staged", "staged"]
+        );
+    }
+
+    #[test]
+    fn test_expand_sections_take_the_field_color() {
+        let template = Template::parse("{icon} {branch}{?staged: +{staged}}{?stash: S}").unwrap();
+        let fields = [
+            field("icon", "G", "blue"),
+            field("branch", "main", "yellow"),
+            field("staged", "3", "green"),
+            field("stash", "0", "blue"),
+        ];
+        let segments = template.expand(&fields, &PromptSegment::default());
+        let parts: Vec<(&str, Option<Color>, bool)> = segments
+            .iter()
+            .map(|s| (s.content.as_str(), s.color.clone(), s.glue))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("G", Some(Color::Blue), false),
+                (" ", None, true),
+                ("main", Some(Color::Yellow), true),
+                (" +3", Some(Color::Green), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_merges_segments_with_the_same_look() {
+        let base = PromptSegment::new_with_color(String::new(), "red").with_style(Style::BOLD);
+        let template = Template::parse("[{{{status}}}]").unwrap();
+        let fields = [(
+            "status",
+            PromptSegment::new_with_color("1".to_string(), "red").with_style(Style::BOLD),
+        )];
+        let segments = template.expand(&fields, &base);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].content, "[{1}]");
+        assert!(!segments[0].glue);
+    }
+}
@@ -0,0 +1,438 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Commands;
+use crate::check::closest;
+use crate::config::Config;
+use crate::modules::git::GitStatusOptions;
+use crate::modules::{Color, SegmentOptions};
+use serde::{Deserialize, Serialize};
+
+/// 組み込みのテーマ。ユーザーのテーマファイルと同じ形式で書かれている。
+pub const BUILTIN_THEMES: &[(&str, &str)] = &[
+    ("default", ""),
+    (
+        "solarized",
+        r##"
+[os]
+color = "#839496"
+bg = "#073642"
+[pwd]
+color = "#268bd2"
+bg = "#073642"
+[time]
+color = "#6c71c4"
+bg = "#073642"
+[cmd]
+success_color = "#859900"
+failure_color = "#dc322f"
+bg = "#073642"
+[env]
+color = "#b58900"
+bg = "#073642"
+[custom]
+bg = "#073642"
+[git]
+bg = "#073642"
+git_icon_color_option = "#268bd2"
+branch_color_option = "#b58900"
+staged_color_option = "#859900"
+unstaged_color_option = "#cb4b16"
+untracked_color_option = "#2aa198"
+conflict_color_option = "#d33682"
+stashed_color_option = "#6c71c4"
+clean_color_option = "#859900"
+ahead_color_option = "#93a1a1"
+behind_color_option = "#dc322f"
+"##,
+    ),
+    (
+        "high-contrast",
+        r##"
+[os]
+color = "bright-white"
+[pwd]
+color = "bright-cyan"
+[time]
+color = "bright-white"
+[cmd]
+success_color = "bright-green"
+failure_color = "bright-red"
+[env]
+color = "bright-yellow"
+[git]
+git_icon_color_option = "bright-white"
+branch_color_option = "bright-yellow"
+branch_style_option = "bold"
+staged_color_option = "bright-green"
+unstaged_color_option = "bright-red"
+untracked_color_option = "bright-cyan"
+conflict_color_option = "bright-magenta"
+conflict_style_option = "bold"
+stashed_color_option = "bright-blue"
+clean_color_option = "bright-green"
+clean_style_option = "none"
+ahead_color_option = "bright-white"
+behind_color_option = "bright-red"
+"##,
+    ),
+    (
+        "monochrome",
+        r##"
+[os]
+color = "default"
+[pwd]
+color = "default"
+[time]
+color = "default"
+[cmd]
+success_color = "default"
+failure_color = "default"
+[env]
+color = "default"
+[git]
+default_color_option = "default"
+branch_style_option = "bold"
+conflict_style_option = "bold,underline"
+"##,
+    ),
+];
+
+/// モジュールの色をまとめて決めるテーマ。
+/// CLIや設定ファイルでモジュールごとに指定した色はテーマより優先される。
+///
+/// ```toml
+/// [pwd]
+/// color = "cyan"
+/// bg = "#073642"
+/// [cmd]
+/// failure_color = "#dc322f"
+/// [git]
+/// branch_color_option = "yellow"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub os: ModuleTheme,
+    pub pwd: ModuleTheme,
+    pub time: ModuleTheme,
+    pub cmd: CmdTheme,
+    pub git: GitTheme,
+    pub env: ModuleTheme,
+    /// 出力の色。未指定なら `custom` は端末の既定の色のまま
+    pub custom: ModuleTheme,
+}
+
+/// 色を1つだけ持つモジュールのテーマ
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModuleTheme {
+    pub color: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+/// `cmd` モジュールのテーマ。直前のコマンドの成否で色を変える。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CmdTheme {
+    pub success_color: Option<Color>,
+    pub failure_color: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+/// `git` モジュールのテーマ。`git` モジュールのオプションと同じ項目に `bg` を加えたもの。
+/// `flatten` と `deny_unknown_fields` は併用できないので、未知の項目は読み込み時に別に調べる。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitTheme {
+    #[serde(flatten)]
+    pub options: GitStatusOptions,
+    pub bg: Option<Color>,
+}
+
+impl GitTheme {
+    /// `[git]` で使える項目。`git` モジュールの設定と同じ名前を使う。
+    pub fn keys() -> Vec<String> {
+        Commands::config_keys("git")
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|key| key.ends_with("_option") || key == "bg")
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum ThemeError {
+    Unknown(String),
+    Io(PathBuf, io::Error),
+    Parse(String, toml::de::Error),
+    /// テーマ名、`[git]` の未知の項目、綴りの近い項目
+    UnknownGitOption(String, String, Option<String>),
+}
+
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThemeError::Unknown(name) => {
+                let names: Vec<&str> = BUILTIN_THEMES.iter().map(|(name, _)| *name).collect();
+                write!(
+                    f,
+                    "Unknown theme: {} (built-in themes: {})",
+                    name,
+                    names.join(", ")
+                )
+            }
+            ThemeError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ThemeError::Parse(name, e) => write!(f, "theme {}: {}", name, e),
+            ThemeError::UnknownGitOption(name, key, suggestion) => {
+                write!(f, "theme {}: unknown option `git.{}`", name, key)?;
+                match suggestion {
+                    Some(known) => write!(f, " (did you mean `{}`?)", known),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ThemeError {}
+
+impl Theme {
+    /// 設定ファイルと同じ場所の `themes` ディレクトリ。`<name>.toml` がテーマ `name` になる。
+    pub fn dir() -> Option<PathBuf> {
+        Some(Config::path()?.parent()?.join("themes"))
+    }
+
+    /// テーマを名前で読み込む。
+    /// パス (`/` を含むか `.toml` で終わる名前) ならそのファイルを、そうでなければ
+    /// `themes` ディレクトリのファイル、組み込みのテーマの順に探す。
+    pub fn load(name: &str) -> Result<Theme, ThemeError> {
+        if name.contains('/') || name.ends_with(".toml") {
+            return Self::load_from(Path::new(name));
+        }
+        if let Some(path) = Self::dir().map(|dir| dir.join(format!("{}.toml", name)))
+            && path.is_file()
+        {
+            return Self::load_from(&path);
+        }
+        let (_, text) = BUILTIN_THEMES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .ok_or_else(|| ThemeError::Unknown(name.to_string()))?;
+        Self::parse(name, text)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Theme, ThemeError> {
+        let text = fs::read_to_string(path).map_err(|e| ThemeError::Io(path.to_path_buf(), e))?;
+        Self::parse(&path.display().to_string(), &text)
+    }
+
+    fn parse(name: &str, text: &str) -> Result<Theme, ThemeError> {
+        let theme = toml::from_str(text).map_err(|e| ThemeError::Parse(name.to_string(), e))?;
+        // 他の節と同じく、`[git]` の未知の項目も誤りとして扱う
+        let table: toml::Table =
+            toml::from_str(text).map_err(|e| ThemeError::Parse(name.to_string(), e))?;
+        if let Some(git) = table.get("git").and_then(toml::Value::as_table) {
+            let keys = GitTheme::keys();
+            if let Some(key) = git.keys().find(|key| !keys.contains(key)) {
+                let suggestion = closest(key, keys.iter().map(String::as_str)).map(str::to_string);
+                return Err(ThemeError::UnknownGitOption(
+                    name.to_string(),
+                    key.clone(),
+                    suggestion,
+                ));
+            }
+        }
+        Ok(theme)
+    }
+
+    /// モジュールで指定されていない色をテーマの色で補う。
+    pub fn apply_to(&self, command: Commands) -> Commands {
+        let color = |theme: &ModuleTheme| theme.color.as_ref().map(|color| color.to_string());
+        let segment = |bg: &Option<Color>| SegmentOptions {
+            bg: bg.clone(),
+            ..Default::default()
+        };
+        let base = match &command {
+            Commands::Os { .. } => Commands::Os {
+                color: color(&self.os),
+                segment: segment(&self.os.bg),
+            },
+            Commands::Pwd { .. } => Commands::Pwd {
+                color: color(&self.pwd),
+                segment: segment(&self.pwd.bg),
+            },
+            Commands::Time { .. } => Commands::Time {
+                color: color(&self.time),
+                segment: segment(&self.time.bg),
+            },
+            Commands::Git { .. } => Commands::Git {
+                options: Box::new(self.git.options.clone()),
+                path: None,
+                segment: segment(&self.git.bg),
+            },
+            Commands::Cmd { last_status, .. } => Commands::Cmd {
+                last_status: last_status.clone(),
+                last_command_executed: None,
+                color: None,
+                success_color: self.cmd.success_color.clone(),
+                failure_color: self.cmd.failure_color.clone(),
+                segment: segment(&self.cmd.bg),
+            },
+            // 実行するコマンドや表示する変数はテーマでは決めない
+            Commands::Custom { .. } => Commands::Custom {
+                command: None,
+                file: None,
+                timeout: None,
+                cache_ttl: None,
+                color: self.custom.color.clone(),
+                segment: segment(&self.custom.bg),
+            },
+            Commands::Env { .. } => Commands::Env {
+                var: Vec::new(),
+                color: self.env.color.clone(),
+                value_color: Vec::new(),
+                icon: None,
+                segment: segment(&self.env.bg),
+            },
+        };
+        command.merged_with(&base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_themes_parse() {
+        for (name, _) in BUILTIN_THEMES {
+            assert!(Theme::load(name).is_ok(), "{}", name);
+        }
+        assert!(matches!(
+            Theme::load("no-such-theme"),
+            Err(ThemeError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_theme_fills_unset_colors_only() {
+        let theme = Theme::load("solarized").unwrap();
+        let commands = Commands::parse_list([
+            "pwd",
+            "::",
+            "git",
+            "--branch-color-option",
+            "red",
+            "::",
+            "cmd",
+            "--last-status",
+            "STATUS",
+        ])
+        .unwrap();
+        let themed: Vec<Commands> = commands
+            .into_iter()
+            .map(|command| theme.apply_to(command))
+            .collect();
+
+        assert!(matches!(&themed[0], Commands::Pwd { color: Some(c), .. } if c == "#268bd2"));
+        let Commands::Git { options, .. } = &themed[1] else {
+            panic!("expected git");
+        };
+        assert_eq!(options.branch_color_option, Some(Color::Red));
+        assert_eq!(
+            options.staged_color_option,
+            Some(Color::Rgb(0x85, 0x99, 0x00))
+        );
+        assert!(matches!(
+            &themed[2],
+            Commands::Cmd { last_status, failure_color: Some(Color::Rgb(0xdc, 0x32, 0x2f)), .. }
+                if last_status == "STATUS"
+        ));
+    }
+
+    #[test]
+    fn test_user_theme_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mine.toml");
+        fs::write(&path, "[time]\ncolor = \"grean\"\n").unwrap();
+        assert!(matches!(
+            Theme::load(path.to_str().unwrap()),
+            Err(ThemeError::Parse(..))
+        ));
+        // パレットの色は描画の前にまとめて引くので、読み込み時は名前のまま残る
+        fs::write(&path, "[time]\ncolor = \"@accent\"\n").unwrap();
+        let theme = Theme::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(theme.time.color, Some(Color::Palette("accent".to_string())));
+
+        fs::write(&path, "[git]\nbranch_color_option = \"green\"\n").unwrap();
+        let theme = Theme::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(theme.git.options.branch_color_option, Some(Color::Green));
+        assert!(theme.pwd.color.is_none());
+
+        // `[git]` は `deny_unknown_fields` が使えないが、綴りの誤りは同じく読み込みエラーになる
+        fs::write(&path, "[git]\nbrnch_color_option = \"green\"\n").unwrap();
+        let error = Theme::load(path.to_str().unwrap()).unwrap_err();
+        assert!(matches!(
+            &error,
+            ThemeError::UnknownGitOption(_, key, Some(known))
+                if key == "brnch_color_option" && known == "branch_color_option"
+        ));
+    }
+
+    #[test]
+    fn test_theme_background_fills_unset_bg_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bg.toml");
+        fs::write(&path, "[pwd]\nbg = \"blue\"\n[git]\nbg = \"black\"\n").unwrap();
+        let theme = Theme::load(path.to_str().unwrap()).unwrap();
+        let commands = Commands::parse_list(["pwd", "::", "git", "--bg", "red", "::", "time"])
+            .unwrap()
+            .into_iter()
+            .map(|command| theme.apply_to(command))
+            .collect::<Vec<_>>();
+        assert_eq!(commands[0].segment_options().bg, Some(Color::Blue));
+        assert_eq!(commands[1].segment_options().bg, Some(Color::Red));
+        assert_eq!(commands[2].segment_options().bg, None);
+    }
+
+    #[test]
+    fn test_theme_colors_env_and_custom_modules() {
+        let theme = Theme::load("monochrome").unwrap();
+        let commands = Commands::parse_list([
+            "env",
+            "--var",
+            "AWS_PROFILE",
+            "::",
+            "env",
+            "--color",
+            "red",
+            "::",
+            "custom",
+            "--command",
+            "echo hi",
+        ])
+        .unwrap()
+        .into_iter()
+        .map(|command| theme.apply_to(command))
+        .collect::<Vec<_>>();
+        assert!(matches!(
+            &commands[0],
+            Commands::Env { var, color: Some(Color::Default), .. } if var == &["AWS_PROFILE"]
+        ));
+        assert!(matches!(
+            &commands[1],
+            Commands::Env {
+                color: Some(Color::Red),
+                ..
+            }
+        ));
+        // テーマに `[custom]` が無ければ端末の既定の色のまま
+        assert!(matches!(
+            &commands[2],
+            Commands::Custom { command: Some(command), color: None, .. } if command == "echo hi"
+        ));
+    }
+}